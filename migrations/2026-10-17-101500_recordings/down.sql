@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS `recording_device_captured_at`;
+DROP TABLE IF EXISTS `recording`;
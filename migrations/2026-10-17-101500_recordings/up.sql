@@ -0,0 +1,11 @@
+-- Your SQL goes here
+CREATE TABLE `recording`(
+	`recording_id` INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+	`device_id` BINARY NOT NULL,
+	`captured_at` TIMESTAMP NOT NULL,
+	`size` INTEGER NOT NULL,
+	`path` TEXT NOT NULL,
+	FOREIGN KEY (`device_id`) REFERENCES `device`(`device_id`)
+);
+
+CREATE INDEX `recording_device_captured_at` ON `recording`(`device_id`, `captured_at`);
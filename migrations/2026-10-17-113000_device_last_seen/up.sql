@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE `device` ADD COLUMN `last_seen` TIMESTAMP;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE `device_tag`;
+ALTER TABLE `device` DROP COLUMN `timezone`;
+ALTER TABLE `device` DROP COLUMN `location`;
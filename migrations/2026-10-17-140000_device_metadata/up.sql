@@ -0,0 +1,10 @@
+-- Your SQL goes here
+ALTER TABLE `device` ADD COLUMN `location` TEXT;
+ALTER TABLE `device` ADD COLUMN `timezone` TEXT;
+
+CREATE TABLE `device_tag`(
+	`device_id` BINARY NOT NULL,
+	`tag` TEXT NOT NULL,
+	PRIMARY KEY (`device_id`, `tag`),
+	FOREIGN KEY (`device_id`) REFERENCES `device`(`device_id`)
+);
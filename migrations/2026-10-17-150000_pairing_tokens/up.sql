@@ -0,0 +1,7 @@
+-- Your SQL goes here
+CREATE TABLE `pairing_token`(
+	`token` BINARY NOT NULL PRIMARY KEY,
+	`user_id` BINARY NOT NULL,
+	`expires_at` TIMESTAMP NOT NULL,
+	FOREIGN KEY (`user_id`) REFERENCES `users`(`user_id`)
+);
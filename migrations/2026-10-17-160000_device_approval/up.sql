@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- Devices registered before approvals existed stay usable
+ALTER TABLE `device` ADD COLUMN `approval` TEXT NOT NULL DEFAULT 'approved';
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+-- SQLite only allows constant defaults when adding columns, so existing rows are updated separately
+ALTER TABLE `device` ADD COLUMN `created_at` TIMESTAMP NOT NULL DEFAULT '1970-01-01 00:00:00';
+UPDATE `device` SET `created_at` = CURRENT_TIMESTAMP;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX `device_mac_address`;
+ALTER TABLE `device` DROP COLUMN `mac_conflict`;
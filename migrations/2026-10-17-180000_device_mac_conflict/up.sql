@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE `device` ADD COLUMN `mac_conflict` BOOL NOT NULL DEFAULT 0;
+
+CREATE INDEX `device_mac_address` ON `device`(`mac_address`);
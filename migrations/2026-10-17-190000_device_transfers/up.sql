@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE `device_transfer`(
+	`device_id` BINARY NOT NULL PRIMARY KEY,
+	`from_user_id` BINARY NOT NULL,
+	`to_user_id` BINARY NOT NULL,
+	`created_at` TIMESTAMP NOT NULL,
+	`expires_at` TIMESTAMP NOT NULL,
+	FOREIGN KEY (`device_id`) REFERENCES `device`(`device_id`),
+	FOREIGN KEY (`from_user_id`) REFERENCES `users`(`user_id`),
+	FOREIGN KEY (`to_user_id`) REFERENCES `users`(`user_id`)
+);
+
+CREATE INDEX `device_transfer_to_user_id` ON `device_transfer`(`to_user_id`);
@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE `device_access`(
+	`device_id` BINARY NOT NULL,
+	`user_id` BINARY NOT NULL,
+	`role` TEXT NOT NULL,
+	`created_at` TIMESTAMP NOT NULL,
+	PRIMARY KEY (`device_id`, `user_id`),
+	FOREIGN KEY (`device_id`) REFERENCES `device`(`device_id`),
+	FOREIGN KEY (`user_id`) REFERENCES `users`(`user_id`)
+);
+
+CREATE INDEX `device_access_user_id` ON `device_access`(`user_id`);
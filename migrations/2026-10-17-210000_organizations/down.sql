@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE `pairing_token` DROP COLUMN `organization_id`;
+ALTER TABLE `device` DROP COLUMN `organization_id`;
+DROP TABLE `organization_member`;
+DROP TABLE `organization`;
@@ -0,0 +1,21 @@
+-- Your SQL goes here
+CREATE TABLE `organization`(
+	`organization_id` BINARY NOT NULL PRIMARY KEY,
+	`name` TEXT NOT NULL,
+	`created_at` TIMESTAMP NOT NULL
+);
+
+CREATE TABLE `organization_member`(
+	`organization_id` BINARY NOT NULL,
+	`user_id` BINARY NOT NULL,
+	`role` TEXT NOT NULL,
+	`created_at` TIMESTAMP NOT NULL,
+	PRIMARY KEY (`organization_id`, `user_id`),
+	FOREIGN KEY (`organization_id`) REFERENCES `organization`(`organization_id`),
+	FOREIGN KEY (`user_id`) REFERENCES `users`(`user_id`)
+);
+
+CREATE INDEX `organization_member_user_id` ON `organization_member`(`user_id`);
+
+ALTER TABLE `device` ADD COLUMN `organization_id` BINARY REFERENCES `organization`(`organization_id`);
+ALTER TABLE `pairing_token` ADD COLUMN `organization_id` BINARY REFERENCES `organization`(`organization_id`);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE `users` DROP COLUMN `disabled`;
+ALTER TABLE `users` DROP COLUMN `is_admin`;
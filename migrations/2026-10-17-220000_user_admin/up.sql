@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE `users` ADD COLUMN `is_admin` BOOL NOT NULL DEFAULT 0;
+ALTER TABLE `users` ADD COLUMN `disabled` BOOL NOT NULL DEFAULT 0;
@@ -0,0 +1,11 @@
+-- Your SQL goes here
+CREATE TABLE `refresh_token`(
+	`token_hash` BINARY NOT NULL PRIMARY KEY,
+	`session_id` BINARY NOT NULL,
+	`user_id` BINARY NOT NULL,
+	`created_at` TIMESTAMP NOT NULL,
+	`expires_at` TIMESTAMP NOT NULL,
+	`rotated` BOOL NOT NULL DEFAULT 0,
+	FOREIGN KEY (`user_id`) REFERENCES `users`(`user_id`)
+);
+CREATE INDEX `refresh_token_session` ON `refresh_token`(`session_id`);
@@ -0,0 +1,241 @@
+use chrono::{DateTime, Utc};
+use diesel::{replace_into, ExpressionMethods, QueryDsl, RunQueryDsl};
+use rocket::{delete, get, put, http::Status, routes, serde::json::Json, Route};
+use serde::{Deserialize, Serialize};
+
+use crate::{auth::AuthenticatedUser, device_routes::find_device, model::{DeviceAccess, DeviceRole, User}, routes_common::{error_response, ErrorResponse}, MainDatabase};
+use crate::schema::device_access::dsl as access_dsl;
+use crate::schema::users::dsl as users_dsl;
+
+pub fn routes() -> Vec<Route> {
+	return routes![
+		list_access,
+		grant_access,
+		revoke_access
+	];
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AccessInfo {
+	pub username: String,
+	pub role: DeviceRole,
+	pub created_at: DateTime<Utc>,
+}
+
+/// Lists users the device is shared with
+#[get("/<id>/access")]
+async fn list_access(database: MainDatabase, auth: AuthenticatedUser, id: &str) -> Result<Json<Vec<AccessInfo>>, ErrorResponse> {
+	let (device, _) = find_device(&database, &auth.user, id, Some(DeviceRole::Admin)).await?;
+	let query = access_dsl::device_access
+		.inner_join(users_dsl::users)
+		.filter(access_dsl::device_id.eq(device.device_id))
+		.order(users_dsl::username);
+	match database.run(move |conn| query.load::<(DeviceAccess, User)>(conn)).await {
+		Ok(shares) => {
+			return Ok(Json::from(shares.into_iter().map(|(access, user)| AccessInfo {
+				username: user.username,
+				role: access.role,
+				created_at: access.created_at.and_utc(),
+			}).collect::<Vec<_>>()));
+		}
+		Err(error) => {
+			log::error!("Error while listing device access: {:?}", error);
+			return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+		}
+	}
+}
+
+/// Finds the user named in the route, like the one the device is shared with
+pub async fn find_user(database: &MainDatabase, username: &str) -> Result<User, ErrorResponse> {
+	let username = String::from(username);
+	match database.run(move |conn| User::find_by_name(conn, &username)).await {
+		Ok(user) => {
+			return Ok(user);
+		}
+		Err(diesel::result::Error::NotFound) => {
+			return Err(error_response(Status::NotFound, "UserNotFound", "User with specified username does not exist"));
+		}
+		Err(error) => {
+			log::error!("Error while retrieving user: {:?}", error);
+			return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+		}
+	}
+}
+
+/// Current share of the device with the user, `None` if the device isn't shared with the user
+async fn find_access(database: &MainDatabase, device_id: Vec<u8>, user_id: Vec<u8>) -> Result<Option<DeviceAccess>, ErrorResponse> {
+	let query = access_dsl::device_access.find((device_id, user_id));
+	match database.run(move |conn| query.first::<DeviceAccess>(conn)).await {
+		Ok(access) => {
+			return Ok(Some(access));
+		}
+		Err(diesel::result::Error::NotFound) => {
+			return Ok(None);
+		}
+		Err(error) => {
+			log::error!("Error while retrieving device access: {:?}", error);
+			return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GrantAccessData {
+	pub role: DeviceRole,
+}
+
+/// Shares the device with the user, or changes the role of the user.
+/// Admins other than the owner or organization admins can only manage viewers and operators
+#[put("/<id>/access/<username>", data = "<data>")]
+async fn grant_access(database: MainDatabase, auth: AuthenticatedUser, id: &str, username: &str, data: Json<GrantAccessData>) -> Result<Json<AccessInfo>, ErrorResponse> {
+	let (device, own_role) = find_device(&database, &auth.user, id, Some(DeviceRole::Admin)).await?;
+	if device.registration_first_stage {
+		return Err(error_response(Status::Conflict, "RegistrationIncomplete", "Device has to finish registration before it can be shared"));
+	}
+	let user = find_user(&database, username).await?;
+	if device.organization_id.is_none() && user.user_id == device.user_id {
+		return Err(error_response(Status::BadRequest, "InvalidUser", "Device can't be shared with its owner"));
+	}
+	if own_role.is_some() {
+		let previous = find_access(&database, device.device_id.clone(), user.user_id.clone()).await?;
+		if data.role == DeviceRole::Admin || previous.is_some_and(|previous| previous.role == DeviceRole::Admin) {
+			return Err(error_response(Status::Forbidden, "InsufficientRole", "Only the owner can manage admins of the device"));
+		}
+	}
+
+	let access = DeviceAccess {
+		device_id: device.device_id,
+		user_id: user.user_id,
+		role: data.role,
+		created_at: Utc::now().naive_utc(),
+	};
+	let inserted = access.clone();
+	if let Err(error) = database.run(move |conn| replace_into(access_dsl::device_access).values(inserted).execute(conn)).await {
+		log::error!("Error while sharing device: {:?}", error);
+		return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+	}
+	return Ok(Json::from(AccessInfo {
+		username: user.username,
+		role: access.role,
+		created_at: access.created_at.and_utc(),
+	}));
+}
+
+/// Stops sharing the device with the user. Any user can remove their own access
+#[delete("/<id>/access/<username>")]
+async fn revoke_access(database: MainDatabase, auth: AuthenticatedUser, id: &str, username: &str) -> Result<Json<AccessInfo>, ErrorResponse> {
+	let leaving = username == auth.user.username;
+	let required = if leaving { DeviceRole::Viewer } else { DeviceRole::Admin };
+	let (device, own_role) = find_device(&database, &auth.user, id, Some(required)).await?;
+	let user = find_user(&database, username).await?;
+	let access = match find_access(&database, device.device_id.clone(), user.user_id.clone()).await? {
+		Some(access) => access,
+		None => {
+			return Err(error_response(Status::NotFound, "AccessNotFound", "Device isn't shared with this user"));
+		}
+	};
+	if !leaving && own_role.is_some() && access.role == DeviceRole::Admin {
+		return Err(error_response(Status::Forbidden, "InsufficientRole", "Only the owner can manage admins of the device"));
+	}
+
+	let query = diesel::delete(access_dsl::device_access.find((device.device_id, user.user_id)));
+	if let Err(error) = database.run(move |conn| query.execute(conn)).await {
+		log::error!("Error while revoking device access: {:?}", error);
+		return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+	}
+	return Ok(Json::from(AccessInfo {
+		username: user.username,
+		role: access.role,
+		created_at: access.created_at.and_utc(),
+	}));
+}
+
+#[cfg(test)]
+mod tests {
+	use rocket::local::asynchronous::Client;
+
+	use crate::{device_routes::{DeviceInfo, UpdateDeviceData}, model::Device, routes_common::Error, tests_common};
+	use super::*;
+
+	async fn share(client: &Client, token: &str, device: &Device, username: &str, role: DeviceRole) -> Status {
+		let response = client.put(format!("/device/{}/access/{}", hex::encode(&device.device_id), username))
+			.header(tests_common::bearer(token))
+			.json(&GrantAccessData { role })
+			.dispatch().await;
+		return response.status();
+	}
+
+	#[rocket::async_test]
+	async fn roles_are_enforced() {
+		let client = tests_common::create_local_async_client().await;
+		let user = tests_common::setup_user(&client).await;
+		let device = tests_common::setup_device(&client, &user).await;
+		let other_user = tests_common::setup_other_user(&client).await;
+		let token = tests_common::login(&client, &user).await;
+		let other_token = tests_common::login(&client, &other_user).await;
+		let url = format!("/device/{}", hex::encode(&device.device_id));
+
+		assert_eq!(share(&client, &other_token, &device, &other_user.username, DeviceRole::Admin).await, Status::NotFound);
+		assert_eq!(share(&client, &token, &device, &user.username, DeviceRole::Viewer).await, Status::BadRequest);
+		assert_eq!(share(&client, &token, &device, &other_user.username, DeviceRole::Viewer).await, Status::Ok);
+
+		let response = client.get("/device").header(tests_common::bearer(&other_token)).dispatch().await;
+		let devices = response.into_json::<Vec<DeviceInfo>>().await.unwrap();
+		assert_eq!(devices.len(), 1);
+		assert_eq!(devices[0].role, Some(DeviceRole::Viewer));
+		let response = client.get(format!("{}/stream.mjpeg", url)).header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		let response = client.patch(url.clone()).header(tests_common::bearer(&other_token)).json(&UpdateDeviceData { name: Some(String::from("Shared")), ..Default::default() }).dispatch().await;
+		assert_eq!(response.status(), Status::Forbidden);
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "InsufficientRole");
+
+		assert_eq!(share(&client, &token, &device, &other_user.username, DeviceRole::Operator).await, Status::Ok);
+		let response = client.patch(url.clone()).header(tests_common::bearer(&other_token)).json(&UpdateDeviceData { name: Some(String::from("Shared")), ..Default::default() }).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		let response = client.delete(url.clone()).header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.status(), Status::Forbidden);
+
+		let response = client.delete(format!("{}/access/{}", url, other_user.username)).header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		let response = client.get(url).header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.status(), Status::NotFound);
+	}
+
+	#[rocket::async_test]
+	async fn only_owner_manages_admins() {
+		let client = tests_common::create_local_async_client().await;
+		let user = tests_common::setup_user(&client).await;
+		let device = tests_common::setup_device(&client, &user).await;
+		let other_user = tests_common::setup_other_user(&client).await;
+		let token = tests_common::login(&client, &user).await;
+		let other_token = tests_common::login(&client, &other_user).await;
+		let response = client.post("/user/register").json(&crate::user_routes::RegisterUserData {
+			email: String::from("third@example.com"),
+			password: String::from("password1"),
+			username: String::from("third_username"),
+		}).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+
+		assert_eq!(share(&client, &token, &device, &other_user.username, DeviceRole::Admin).await, Status::Ok);
+		assert_eq!(share(&client, &other_token, &device, "third_username", DeviceRole::Admin).await, Status::Forbidden);
+		assert_eq!(share(&client, &other_token, &device, "third_username", DeviceRole::Operator).await, Status::Ok);
+		assert_eq!(share(&client, &other_token, &device, &other_user.username, DeviceRole::Viewer).await, Status::Forbidden);
+
+		let url = format!("/device/{}/access", hex::encode(&device.device_id));
+		let response = client.get(url.clone()).header(tests_common::bearer(&other_token)).dispatch().await;
+		let shares = response.into_json::<Vec<AccessInfo>>().await.unwrap();
+		assert_eq!(shares.iter().map(|access| (access.username.as_str(), access.role)).collect::<Vec<_>>(), vec![
+			(other_user.username.as_str(), DeviceRole::Admin),
+			("third_username", DeviceRole::Operator),
+		]);
+
+		let response = client.delete(format!("{}/third_username", url)).header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		let response = client.delete(format!("{}/third_username", url)).header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "AccessNotFound");
+		let response = client.delete(format!("{}/{}", url, other_user.username)).header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		let response = client.get(url).header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.status(), Status::NotFound);
+	}
+}
@@ -0,0 +1,328 @@
+use chrono::{DateTime, Utc};
+use diesel::{update, BoolExpressionMethods, EscapeExpressionMethods, ExpressionMethods, QueryDsl, RunQueryDsl, TextExpressionMethods};
+use rocket::{fairing::AdHoc, get, patch, post, http::Status, routes, serde::json::Json, Route, State};
+use serde::{Deserialize, Serialize};
+
+use crate::{auth::AdminUser, device_connector::{BridgeStats, DeviceBridge}, device_routes::{load_tags, parse_device_id, DeviceInfo}, model::{Device, User}, routes_common::{error_response, ErrorResponse}, MainDatabase};
+use crate::schema::device::dsl as device_dsl;
+use crate::schema::users::dsl as users_dsl;
+
+/// Page size used when `limit` isn't given
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+diesel::define_sql_function! {
+	/// SQLite `hex`, which encodes blobs as uppercase hexadecimal text
+	#[sql_name = "hex"]
+	fn sql_hex(value: diesel::sql_types::Binary) -> diesel::sql_types::Text;
+}
+
+pub fn routes() -> Vec<Route> {
+	return routes![
+		list_users,
+		update_user,
+		list_devices,
+		disconnect_device,
+		stats
+	];
+}
+
+/// Gives the admin flag to users listed in the `admins` config key, so the first administrator doesn't have to be set in the database.
+/// Users that register later are promoted on the next start
+pub fn fairing() -> AdHoc {
+	return AdHoc::on_ignite("Promote administrators", |rocket| async {
+		let admins: Vec<String> = rocket.figment().extract_inner("admins").unwrap_or_default();
+		if admins.is_empty() {
+			return rocket;
+		}
+		let Some(database) = MainDatabase::get_one(&rocket).await else {
+			log::error!("Can't promote administrators, database is not available");
+			return rocket;
+		};
+		let query = update(users_dsl::users.filter(users_dsl::username.eq_any(admins.clone()))).set(users_dsl::is_admin.eq(true));
+		match database.run(move |conn| query.execute(conn)).await {
+			Ok(promoted) if promoted < admins.len() => {
+				log::warn!("Only {} of {} configured administrators exist", promoted, admins.len());
+			}
+			Ok(_) => {}
+			Err(error) => {
+				log::error!("Error while promoting administrators: {:?}", error);
+			}
+		}
+		return rocket;
+	});
+}
+
+/// Turns user input into a `LIKE` pattern matching it anywhere, with wildcards in the input escaped
+fn contains_pattern(search: &str) -> String {
+	let escaped = search.trim().replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+	return format!("%{}%", escaped);
+}
+
+fn page_size(limit: Option<i64>) -> i64 {
+	return limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AdminUserInfo {
+	/// Hex encoded
+	pub user_id: String,
+	pub username: String,
+	pub email: String,
+	pub is_admin: bool,
+	pub disabled: bool,
+}
+
+impl From<User> for AdminUserInfo {
+	fn from(user: User) -> Self {
+		return Self {
+			user_id: hex::encode(user.user_id),
+			username: user.username,
+			email: user.email,
+			is_admin: user.is_admin,
+			disabled: user.disabled,
+		};
+	}
+}
+
+/// Lists users ordered by username. With `search` given, only users whose username or email contains it are listed
+#[get("/users?<search>&<offset>&<limit>")]
+async fn list_users(database: MainDatabase, _admin: AdminUser, search: Option<&str>, offset: Option<i64>, limit: Option<i64>) -> Result<Json<Vec<AdminUserInfo>>, ErrorResponse> {
+	let pattern = contains_pattern(search.unwrap_or_default());
+	let query = users_dsl::users
+		.filter(users_dsl::username.like(pattern.clone()).escape('\\').or(users_dsl::email.like(pattern).escape('\\')))
+		.order(users_dsl::username)
+		.offset(offset.unwrap_or(0).max(0))
+		.limit(page_size(limit));
+	match database.run(move |conn| query.load::<User>(conn)).await {
+		Ok(users) => {
+			return Ok(Json::from(users.into_iter().map(AdminUserInfo::from).collect::<Vec<_>>()));
+		}
+		Err(error) => {
+			log::error!("Error while listing users: {:?}", error);
+			return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct UpdateUserData {
+	pub disabled: Option<bool>,
+	pub is_admin: Option<bool>,
+}
+
+/// Disables or enables the account, or changes its admin flag. Administrators can't change their own account,
+/// so there is always at least one administrator left
+#[patch("/users/<username>", data = "<data>")]
+async fn update_user(database: MainDatabase, admin: AdminUser, username: &str, data: Json<UpdateUserData>) -> Result<Json<AdminUserInfo>, ErrorResponse> {
+	if username == admin.user.username {
+		return Err(error_response(Status::BadRequest, "SelfModification", "Administrators can't disable or demote themselves"));
+	}
+	let query = users_dsl::users.filter(users_dsl::username.eq(String::from(username)));
+	let mut user = match database.run(move |conn| query.first::<User>(conn)).await {
+		Ok(user) => user,
+		Err(diesel::result::Error::NotFound) => {
+			return Err(error_response(Status::NotFound, "UserNotFound", "User with specified username does not exist"));
+		}
+		Err(error) => {
+			log::error!("Error while retrieving user: {:?}", error);
+			return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+		}
+	};
+	user.disabled = data.disabled.unwrap_or(user.disabled);
+	user.is_admin = data.is_admin.unwrap_or(user.is_admin);
+
+	let query = update(users_dsl::users.find(user.user_id.clone())).set((users_dsl::disabled.eq(user.disabled), users_dsl::is_admin.eq(user.is_admin)));
+	if let Err(error) = database.run(move |conn| query.execute(conn)).await {
+		log::error!("Error while updating user: {:?}", error);
+		return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+	}
+	log::info!("Administrator {} set user {} disabled: {}, admin: {}", admin.user.username, user.username, user.disabled, user.is_admin);
+	return Ok(Json::from(AdminUserInfo::from(user)));
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AdminDeviceInfo {
+	/// User that owns or registered the device
+	pub owner_username: String,
+	#[serde(flatten)]
+	pub device: DeviceInfo,
+}
+
+/// Lists devices of all users ordered by ID. With `search` given, only devices whose hex encoded ID or MAC address,
+/// or name contains it are listed
+#[get("/devices?<search>&<offset>&<limit>")]
+async fn list_devices(database: MainDatabase, bridge: &State<DeviceBridge>, _admin: AdminUser, search: Option<&str>, offset: Option<i64>, limit: Option<i64>) -> Result<Json<Vec<AdminDeviceInfo>>, ErrorResponse> {
+	let search = search.unwrap_or_default();
+	let hex_pattern = contains_pattern(&search.to_uppercase());
+	let name_pattern = contains_pattern(search);
+	let query = device_dsl::device
+		.inner_join(users_dsl::users)
+		.filter(sql_hex(device_dsl::device_id).like(hex_pattern.clone()).escape('\\')
+			.or(sql_hex(device_dsl::mac_address).like(hex_pattern).escape('\\'))
+			.or(device_dsl::name.like(name_pattern).escape('\\')))
+		.order(device_dsl::device_id)
+		.offset(offset.unwrap_or(0).max(0))
+		.limit(page_size(limit));
+	let devices = match database.run(move |conn| query.load::<(Device, User)>(conn)).await {
+		Ok(devices) => devices,
+		Err(error) => {
+			log::error!("Error while listing devices: {:?}", error);
+			return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+		}
+	};
+	let mut tags = load_tags(&database, devices.iter().map(|(device, _)| device.device_id.clone()).collect()).await?;
+	return Ok(Json::from(devices.into_iter().map(|(device, owner)| {
+		let device_tags = tags.remove(&device.device_id).unwrap_or_default();
+		return AdminDeviceInfo {
+			owner_username: owner.username,
+			device: DeviceInfo::new(device, None, device_tags, bridge),
+		};
+	}).collect::<Vec<_>>()));
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DisconnectResult {
+	/// Start of the session that was ended
+	pub connected_since: DateTime<Utc>,
+}
+
+/// Ends the session of the device. The device is told to reconnect later, so this doesn't stop it for good
+#[post("/devices/<id>/disconnect")]
+async fn disconnect_device(bridge: &State<DeviceBridge>, admin: AdminUser, id: &str) -> Result<Json<DisconnectResult>, ErrorResponse> {
+	let device_id: [u8; 16] = parse_device_id(id)?.try_into().unwrap();
+	let Some(session) = bridge.device_session(device_id) else {
+		return Err(error_response(Status::Conflict, "DeviceOffline", "Device is not connected"));
+	};
+	if !bridge.disconnect(device_id) {
+		return Err(error_response(Status::Conflict, "DeviceOffline", "Device is not connected"));
+	}
+	log::info!("Administrator {} disconnected device {:?}", admin.user.username, device_id);
+	return Ok(Json::from(DisconnectResult { connected_since: session.connected_at }));
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AdminStats {
+	pub users: i64,
+	pub devices: i64,
+	pub bridge: BridgeStats,
+}
+
+/// Counts users and devices, and reports the state of the device bridge
+#[get("/stats")]
+async fn stats(database: MainDatabase, bridge: &State<DeviceBridge>, _admin: AdminUser) -> Result<Json<AdminStats>, ErrorResponse> {
+	let counted = database.run(|conn| {
+		let users = users_dsl::users.count().get_result::<i64>(conn)?;
+		let devices = device_dsl::device.count().get_result::<i64>(conn)?;
+		return Ok::<_, diesel::result::Error>((users, devices));
+	}).await;
+	match counted {
+		Ok((users, devices)) => {
+			return Ok(Json::from(AdminStats { users, devices, bridge: bridge.stats() }));
+		}
+		Err(error) => {
+			log::error!("Error while counting users and devices: {:?}", error);
+			return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use rocket::{futures::StreamExt, local::asynchronous::Client};
+
+	use crate::{device_connector::packets::Message, routes_common::Error, tests_common, user_routes::LoginUserData};
+	use super::*;
+
+	async fn promote(client: &Client, user: &User) {
+		let database = MainDatabase::get_one(client.rocket()).await.unwrap();
+		let query = update(users_dsl::users.find(user.user_id.clone())).set(users_dsl::is_admin.eq(true));
+		database.run(move |conn| query.execute(conn)).await.unwrap();
+	}
+
+	#[rocket::async_test]
+	async fn admin_required() {
+		let client = tests_common::create_local_async_client().await;
+		let user = tests_common::setup_user(&client).await;
+		let token = tests_common::login(&client, &user).await;
+
+		let response = client.get("/admin/users").dispatch().await;
+		assert_eq!(response.status(), Status::Unauthorized);
+		let response = client.get("/admin/users").header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::Forbidden);
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "NotAdmin");
+
+		promote(&client, &user).await;
+		let response = client.get("/admin/stats").header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		let stats = response.into_json::<AdminStats>().await.unwrap();
+		assert_eq!(stats.users, 1);
+		assert_eq!(stats.bridge.connected_devices, 0);
+	}
+
+	#[rocket::async_test]
+	async fn disable_user() {
+		let client = tests_common::create_local_async_client().await;
+		let user = tests_common::setup_user(&client).await;
+		let other_user = tests_common::setup_other_user(&client).await;
+		promote(&client, &user).await;
+		let token = tests_common::login(&client, &user).await;
+		let other_token = tests_common::login(&client, &other_user).await;
+
+		let response = client.get("/admin/users?search=other").header(tests_common::bearer(&token)).dispatch().await;
+		let users = response.into_json::<Vec<AdminUserInfo>>().await.unwrap();
+		assert_eq!(users.len(), 1);
+		assert_eq!(users[0].username, other_user.username);
+		let response = client.get("/admin/users?search=%25").header(tests_common::bearer(&token)).dispatch().await;
+		assert!(response.into_json::<Vec<AdminUserInfo>>().await.unwrap().is_empty());
+
+		let response = client.patch(format!("/admin/users/{}", user.username)).header(tests_common::bearer(&token)).json(&UpdateUserData { disabled: Some(true), ..Default::default() }).dispatch().await;
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "SelfModification");
+		let response = client.patch(format!("/admin/users/{}", other_user.username)).header(tests_common::bearer(&token)).json(&UpdateUserData { disabled: Some(true), ..Default::default() }).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		assert!(response.into_json::<AdminUserInfo>().await.unwrap().disabled);
+
+		let response = client.get("/user/me").header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.status(), Status::Forbidden);
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "AccountDisabled");
+		let response = client.post("/user/login").json(&LoginUserData { username: other_user.username.clone(), password: other_user.password.clone() }).dispatch().await;
+		assert_eq!(response.status(), Status::Forbidden);
+
+		let response = client.patch(format!("/admin/users/{}", other_user.username)).header(tests_common::bearer(&token)).json(&UpdateUserData { disabled: Some(false), ..Default::default() }).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		let response = client.get("/user/me").header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+	}
+
+	#[rocket::async_test]
+	async fn devices_and_disconnect() {
+		let client = tests_common::create_local_async_client().await;
+		let user = tests_common::setup_user(&client).await;
+		let other_user = tests_common::setup_other_user(&client).await;
+		let device = tests_common::setup_device(&client, &other_user).await;
+		promote(&client, &user).await;
+		let token = tests_common::login(&client, &user).await;
+
+		let response = client.get(format!("/admin/devices?search={}", hex::encode(&device.mac_address))).header(tests_common::bearer(&token)).dispatch().await;
+		let devices = response.into_json::<Vec<AdminDeviceInfo>>().await.unwrap();
+		assert_eq!(devices.len(), 1);
+		assert_eq!(devices[0].owner_username, other_user.username);
+		assert_eq!(devices[0].device.device_id, hex::encode(&device.device_id));
+		let response = client.get("/admin/devices?search=ffff").header(tests_common::bearer(&token)).dispatch().await;
+		assert!(response.into_json::<Vec<AdminDeviceInfo>>().await.unwrap().is_empty());
+
+		let url = format!("/admin/devices/{}/disconnect", hex::encode(&device.device_id));
+		let response = client.post(url.clone()).header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "DeviceOffline");
+
+		let bridge = client.rocket().state::<DeviceBridge>().unwrap();
+		let (mut connection, _) = tests_common::connect_device(&client, &device).await;
+		assert_eq!(bridge.stats().connected_devices, 1);
+
+		let response = client.post(url).header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		let goodbye = connection.next().await.unwrap().unwrap();
+		assert!(matches!(goodbye.message, Message::Disconnect(_)));
+		assert!(connection.next().await.is_none());
+	}
+}
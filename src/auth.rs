@@ -1,15 +1,382 @@
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
+use diesel::{QueryDsl, RunQueryDsl};
+use jsonwebtoken::{decode, decode_header, encode, errors::ErrorKind, DecodingKey, EncodingKey, Header, Validation};
+use rocket::{catch, catchers, http::Status, request::{FromRequest, Outcome}, serde::json::Json, Catcher, Request};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{model::{RefreshToken, User}, routes_common::{error_response, Error, ErrorResponse}, MainDatabase};
+use crate::schema::users::dsl as users_dsl;
+
+pub fn catchers() -> Vec<Catcher> {
+	return catchers![
+		unauthorized,
+		forbidden
+	];
+}
+
+const TOKEN_ISSUER: &str = "camera-server";
+const TOKEN_AUDIENCE: &str = "camera-server-api";
+const STREAM_TOKEN_AUDIENCE: &str = "camera-server-stream";
+/// Key ID of `JWT_SECRET` when `JWT_KEY_ID` is not set
+const DEFAULT_KEY_ID: &str = "default";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AuthToken {
+	/// Hex encoded ID of the user
+	pub sub: String,
+	/// Username at the time of issuing, users are looked up by `sub`
 	pub username: String,
+	/// Hex encoded ID of the session started by logging in, shared with its refresh tokens
+	pub session: String,
+	/// Unique ID of the token
+	pub jti: String,
+	pub iss: String,
+	pub aud: String,
+	/// Issue time
+	pub iat: i64,
 	/// Expiration time
 	pub exp: i64
 }
 
 impl AuthToken {
-	pub fn new(username: String, expiry: DateTime<Utc>) -> Self {
-		return Self { username, exp: expiry.timestamp() };
+	pub fn new(user_id: &[u8], username: String, session_id: &[u8], expiry: DateTime<Utc>) -> Self {
+		return Self {
+			sub: hex::encode(user_id),
+			username,
+			session: hex::encode(session_id),
+			jti: hex::encode(rand::random::<[u8; 16]>()),
+			iss: String::from(TOKEN_ISSUER),
+			aud: String::from(TOKEN_AUDIENCE),
+			iat: Utc::now().timestamp(),
+			exp: expiry.timestamp(),
+		};
+	}
+
+	/// Signs the token with `JWT_SECRET`, putting `JWT_KEY_ID` into the header
+	pub fn encode(&self) -> String {
+		return encode_claims(self);
+	}
+
+	/// Verifies the signature, expiration time, issuer and audience of an encoded token.
+	/// Tokens signed with a key from `JWT_PREVIOUS_SECRETS` are accepted too, so the secret can be rotated without logging everyone out
+	pub fn decode(token: &str) -> Result<Self, jsonwebtoken::errors::Error> {
+		return decode_claims(token, TOKEN_AUDIENCE);
+	}
+}
+
+/// Short-lived token that opens a live stream of a single device, for clients that can't set the `Authorization` header and pass it in the URL.
+/// It isn't accepted by any other route, so a leaked URL doesn't give access to the account
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StreamToken {
+	/// Hex encoded ID of the user
+	pub sub: String,
+	/// Hex encoded ID of the device that can be streamed
+	pub device: String,
+	/// Hex encoded ID of the session the token was issued in, logging it out revokes the token
+	pub session: String,
+	pub iss: String,
+	pub aud: String,
+	/// Issue time
+	pub iat: i64,
+	/// Expiration time
+	pub exp: i64
+}
+
+impl StreamToken {
+	pub fn new(user_id: &[u8], device_id: &[u8], session_id: &[u8], expiry: DateTime<Utc>) -> Self {
+		return Self {
+			sub: hex::encode(user_id),
+			device: hex::encode(device_id),
+			session: hex::encode(session_id),
+			iss: String::from(TOKEN_ISSUER),
+			aud: String::from(STREAM_TOKEN_AUDIENCE),
+			iat: Utc::now().timestamp(),
+			exp: expiry.timestamp(),
+		};
+	}
+
+	/// Same as [`AuthToken::encode`]
+	pub fn encode(&self) -> String {
+		return encode_claims(self);
+	}
+
+	/// Same as [`AuthToken::decode`], tokens of other audiences are rejected
+	pub fn decode(token: &str) -> Result<Self, jsonwebtoken::errors::Error> {
+		return decode_claims(token, STREAM_TOKEN_AUDIENCE);
+	}
+}
+
+fn encode_claims<T: Serialize>(claims: &T) -> String {
+	let token_secret = std::env::var("JWT_SECRET").expect("JWT_SECRET is not set");
+	let mut header = Header::new(jsonwebtoken::Algorithm::HS512);
+	header.kid = Some(std::env::var("JWT_KEY_ID").unwrap_or_else(|_| String::from(DEFAULT_KEY_ID)));
+	return encode(&header, claims, &EncodingKey::from_secret(token_secret.as_bytes())).unwrap();
+}
+
+fn decode_claims<T: DeserializeOwned>(token: &str, audience: &str) -> Result<T, jsonwebtoken::errors::Error> {
+	let key_id = decode_header(token)?.kid.ok_or(ErrorKind::InvalidToken)?;
+	let token_secret = std::env::var("JWT_SECRET").expect("JWT_SECRET is not set");
+	let current_key_id = std::env::var("JWT_KEY_ID").unwrap_or_else(|_| String::from(DEFAULT_KEY_ID));
+	let previous_secrets = std::env::var("JWT_PREVIOUS_SECRETS").unwrap_or_default();
+	let secret = find_secret(&key_id, &current_key_id, &token_secret, &previous_secrets).ok_or(ErrorKind::InvalidToken)?;
+
+	let mut validation = Validation::new(jsonwebtoken::Algorithm::HS512);
+	validation.set_issuer(&[TOKEN_ISSUER]);
+	validation.set_audience(&[audience]);
+	validation.set_required_spec_claims(&["exp", "iat", "sub", "iss", "aud"]);
+	let decoded = decode::<T>(token, &DecodingKey::from_secret(secret.as_bytes()), &validation)?;
+	return Ok(decoded.claims);
+}
+
+/// Picks the secret with `key_id`, either the current one or one of `previous`, given as comma separated `key_id:secret` pairs
+fn find_secret<'a>(key_id: &str, current_key_id: &str, current: &'a str, previous: &'a str) -> Option<&'a str> {
+	if key_id == current_key_id {
+		return Some(current);
+	}
+	return previous.split(',')
+		.filter_map(|pair| pair.trim().split_once(':'))
+		.find(|(previous_id, _)| *previous_id == key_id)
+		.map(|(_, secret)| secret);
+}
+
+/// Reasons for rejecting a request that requires a logged in user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+	MissingToken,
+	/// Malformed token or bad signature
+	InvalidToken,
+	ExpiredToken,
+	/// Token is valid, but its user was removed
+	UserNotFound,
+	/// Session of the token was logged out
+	RevokedToken,
+	/// User was disabled by an administrator
+	AccountDisabled,
+	/// Route requires an administrator
+	NotAdmin,
+	DatabaseError,
+}
+
+impl AuthError {
+	pub fn status(self) -> Status {
+		match self {
+			AuthError::DatabaseError => Status::InternalServerError,
+			AuthError::AccountDisabled | AuthError::NotAdmin => Status::Forbidden,
+			_ => Status::Unauthorized,
+		}
+	}
+
+	fn body(self) -> Error {
+		let (code, explanation) = match self {
+			AuthError::MissingToken => ("MissingToken", "Authorization token is required"),
+			AuthError::InvalidToken => ("InvalidToken", "Missing or invalid authorization token"),
+			AuthError::ExpiredToken => ("ExpiredToken", "Authorization token has expired, log in again"),
+			AuthError::UserNotFound => ("UserNotFound", "User from the token does not exist"),
+			AuthError::RevokedToken => ("RevokedToken", "Authorization token was logged out, log in again"),
+			AuthError::AccountDisabled => ("AccountDisabled", "The account was disabled by an administrator"),
+			AuthError::NotAdmin => ("NotAdmin", "Only administrators can do this"),
+			AuthError::DatabaseError => ("InternalError", "Unknown error. Contact administrator."),
+		};
+		return Error { code: String::from(code), explanation: String::from(explanation) };
+	}
+
+	pub fn response(self) -> ErrorResponse {
+		let Error { code, explanation } = self.body();
+		return error_response(self.status(), &code, &explanation);
+	}
+}
+
+/// User that sent the request, identified by the token from the `Authorization: Bearer <token>` header
+#[derive(Debug)]
+pub struct AuthenticatedUser {
+	pub user: User,
+	/// Session the token belongs to, ended by logging out
+	pub session_id: Vec<u8>,
+}
+
+impl AuthenticatedUser {
+	/// Verifies the encoded token and loads its user
+	pub async fn authenticate(database: &MainDatabase, token: &str) -> Result<Self, AuthError> {
+		let token = AuthToken::decode(token).map_err(rejection)?;
+		return Self::load(database, &token.sub, &token.session).await;
+	}
+
+	/// Verifies the encoded [`StreamToken`] was issued for the device and loads its user
+	pub async fn authenticate_stream(database: &MainDatabase, token: &str, device_id: &[u8]) -> Result<Self, AuthError> {
+		let token = StreamToken::decode(token).map_err(rejection)?;
+		if token.device != hex::encode(device_id) {
+			return Err(AuthError::InvalidToken);
+		}
+		return Self::load(database, &token.sub, &token.session).await;
+	}
+
+	/// Loads the user with hex encoded `user_id`, checking the session is still logged in
+	async fn load(database: &MainDatabase, user_id: &str, session_id: &str) -> Result<Self, AuthError> {
+		let (Ok(user_id), Ok(session_id)) = (hex::decode(user_id), hex::decode(session_id)) else {
+			return Err(AuthError::InvalidToken);
+		};
+
+		let query = users_dsl::users.find(user_id);
+		let session = session_id.clone();
+		let result = database.run(move |conn| -> diesel::QueryResult<(User, bool)> {
+			let user = query.first::<User>(conn)?;
+			let active = RefreshToken::session_active(conn, session, user.user_id.clone())?;
+			return Ok((user, active));
+		}).await;
+		match result {
+			Ok((user, _)) if user.disabled => {
+				return Err(AuthError::AccountDisabled);
+			}
+			Ok((_, false)) => {
+				return Err(AuthError::RevokedToken);
+			}
+			Ok((user, true)) => {
+				return Ok(Self { user, session_id });
+			}
+			Err(diesel::result::Error::NotFound) => {
+				return Err(AuthError::UserNotFound);
+			}
+			Err(error) => {
+				log::error!("Error while retrieving user of a token: {:?}", error);
+				return Err(AuthError::DatabaseError);
+			}
+		}
+	}
+}
+
+/// Turns a token decoding error into the reason reported to the client
+fn rejection(err: jsonwebtoken::errors::Error) -> AuthError {
+	if *err.kind() == ErrorKind::ExpiredSignature {
+		return AuthError::ExpiredToken;
+	}
+	log::debug!("Rejected authorization token: {}", err);
+	return AuthError::InvalidToken;
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthenticatedUser {
+	type Error = AuthError;
+
+	async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+		let result = match request.headers().get_one("Authorization") {
+			None => Err(AuthError::MissingToken),
+			Some(header) => match header.strip_prefix("Bearer ") {
+				None => Err(AuthError::InvalidToken),
+				Some(token) => match request.guard::<MainDatabase>().await {
+					Outcome::Success(database) => Self::authenticate(&database, token).await,
+					_ => Err(AuthError::DatabaseError),
+				},
+			},
+		};
+		match result {
+			Ok(user) => {
+				return Outcome::Success(user);
+			}
+			Err(err) => {
+				// Read by the catcher to explain the rejection
+				request.local_cache(|| Some(err));
+				return Outcome::Error((err.status(), err));
+			}
+		}
 	}
-}
\ No newline at end of file
+}
+
+/// Logged in user with the admin flag set
+#[derive(Debug)]
+pub struct AdminUser {
+	pub user: User,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminUser {
+	type Error = AuthError;
+
+	async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+		match request.guard::<AuthenticatedUser>().await {
+			Outcome::Success(auth) if auth.user.is_admin => {
+				return Outcome::Success(Self { user: auth.user });
+			}
+			Outcome::Success(_) => {
+				request.local_cache(|| Some(AuthError::NotAdmin));
+				return Outcome::Error((Status::Forbidden, AuthError::NotAdmin));
+			}
+			Outcome::Error(err) => {
+				return Outcome::Error(err);
+			}
+			Outcome::Forward(status) => {
+				return Outcome::Forward(status);
+			}
+		}
+	}
+}
+
+/// Explains why [`AuthenticatedUser`] rejected the request, in the same format as other API errors
+#[catch(401)]
+fn unauthorized(request: &Request) -> Json<Error> {
+	let error = request.local_cache(|| None::<AuthError>).unwrap_or(AuthError::InvalidToken);
+	return Json::from(error.body());
+}
+
+/// Same as [`unauthorized`], for disabled accounts and users that aren't administrators
+#[catch(403)]
+fn forbidden(request: &Request) -> Json<Error> {
+	let error = request.local_cache(|| None::<AuthError>).unwrap_or(AuthError::NotAdmin);
+	return Json::from(error.body());
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{tests_common, user_routes::UserInfo};
+	use super::*;
+
+	#[rocket::async_test]
+	async fn guard_errors() {
+		let client = tests_common::create_local_async_client().await;
+		let user = tests_common::setup_user(&client).await;
+
+		let response = client.get("/user/me").dispatch().await;
+		assert_eq!(response.status(), Status::Unauthorized);
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "MissingToken");
+
+		let response = client.get("/user/me").header(tests_common::bearer("invalid")).dispatch().await;
+		assert_eq!(response.status(), Status::Unauthorized);
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "InvalidToken");
+
+		let expired = AuthToken::new(&user.user_id, user.username.clone(), &[0; 16], Utc::now() - chrono::Duration::hours(1)).encode();
+		let response = client.get("/user/me").header(tests_common::bearer(&expired)).dispatch().await;
+		assert_eq!(response.status(), Status::Unauthorized);
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "ExpiredToken");
+
+		let removed = AuthToken::new(&[9; 16], String::from("removed_user"), &[0; 16], Utc::now() + chrono::Duration::hours(1)).encode();
+		let response = client.get("/user/me").header(tests_common::bearer(&removed)).dispatch().await;
+		assert_eq!(response.status(), Status::Unauthorized);
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "UserNotFound");
+
+		let unknown_session = AuthToken::new(&user.user_id, user.username.clone(), &[0; 16], Utc::now() + chrono::Duration::hours(1)).encode();
+		let response = client.get("/user/me").header(tests_common::bearer(&unknown_session)).dispatch().await;
+		assert_eq!(response.status(), Status::Unauthorized);
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "RevokedToken");
+
+		let mut foreign = AuthToken::new(&user.user_id, user.username.clone(), &[0; 16], Utc::now() + chrono::Duration::hours(1));
+		foreign.aud = String::from("other-service");
+		let response = client.get("/user/me").header(tests_common::bearer(&foreign.encode())).dispatch().await;
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "InvalidToken");
+
+		let token = tests_common::login(&client, &user).await;
+		let response = client.get("/user/me").header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		let info = response.into_json::<UserInfo>().await.unwrap();
+		assert_eq!(info.user_id, hex::encode(&user.user_id));
+		assert_eq!(info.username, user.username);
+	}
+
+	#[test]
+	fn previous_secrets() {
+		let previous = "old:old_secret, older:older_secret";
+		assert_eq!(find_secret("new", "new", "new_secret", previous), Some("new_secret"));
+		assert_eq!(find_secret("old", "new", "new_secret", previous), Some("old_secret"));
+		assert_eq!(find_secret("older", "new", "new_secret", previous), Some("older_secret"));
+		assert_eq!(find_secret("unknown", "new", "new_secret", previous), None);
+		assert_eq!(find_secret("old", "new", "new_secret", ""), None);
+	}
+}
@@ -0,0 +1,198 @@
+use deku::{DekuContainerRead, DekuContainerWrite};
+use rocket::tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::{bytes::{Buf, BytesMut}, codec::{Decoder, Encoder, Framed}};
+
+use super::packets::{ApplicationPacket, Message, PacketHeader, PacketReadError, HEADER_SIZE};
+
+/// Device connection that reads and writes whole [`ApplicationPacket`]s
+pub type PacketStream<T> = Framed<T, ApplicationPacketCodec>;
+
+/// Splits a byte stream into [`ApplicationPacket`]s using `buffer_size` from the header.
+/// Packets that can't be parsed or have an unknown message ID are logged and skipped,
+/// since their body is consumed and the stream stays at the start of the next packet.
+/// Only errors after which the stream can't be read further are returned
+#[derive(Debug, Clone)]
+pub struct ApplicationPacketCodec {
+	/// Largest allowed packet body, bigger packets are an error
+	max_body_size: u32,
+}
+
+impl ApplicationPacketCodec {
+	pub fn new(max_body_size: u32) -> Self {
+		return Self { max_body_size };
+	}
+
+	pub fn framed<T: AsyncRead + AsyncWrite>(self, io: T) -> PacketStream<T> {
+		return Framed::new(io, self);
+	}
+
+	/// Parses one whole packet, the slice has to contain exactly the header and the body
+	fn parse_packet(packet_bytes: &[u8]) -> Result<ApplicationPacket, PacketReadError> {
+		let message_id = packet_bytes[HEADER_SIZE - 1] & 0b0111_1111;
+		if !Message::is_known_id(message_id) {
+			return Err(PacketReadError::UnknownMessageId(message_id));
+		}
+		match ApplicationPacket::from_bytes((packet_bytes, 0)) {
+			Ok((_, packet)) => {
+				return Ok(packet);
+			}
+			Err(err) => {
+				return Err(PacketReadError::PacketParseError(err.to_string()));
+			}
+		}
+	}
+}
+
+impl Decoder for ApplicationPacketCodec {
+	type Item = ApplicationPacket;
+	type Error = PacketReadError;
+
+	fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+		loop {
+			if src.len() < HEADER_SIZE {
+				return Ok(None);
+			}
+			let header = match PacketHeader::from_bytes((&src[..HEADER_SIZE], 0)) {
+				Ok((_, header)) => header,
+				Err(err) => {
+					return Err(PacketReadError::HeaderParseError(err.to_string()));
+				}
+			};
+			if header.buffer_size > self.max_body_size {
+				return Err(PacketReadError::Oversized(header.buffer_size));
+			}
+			let packet_size = HEADER_SIZE + header.buffer_size as usize;
+			if src.len() < packet_size {
+				src.reserve(packet_size - src.len());
+				return Ok(None);
+			}
+
+			let result = Self::parse_packet(&src[..packet_size]);
+			src.advance(packet_size);
+			match result {
+				Ok(packet) => {
+					return Ok(Some(packet));
+				}
+				Err(err) if !err.is_fatal() => {
+					log::warn!("Skipping packet: {}", err);
+				}
+				Err(err) => {
+					return Err(err);
+				}
+			}
+		}
+	}
+
+	fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+		match self.decode(buf)? {
+			Some(packet) => {
+				return Ok(Some(packet));
+			}
+			None if buf.is_empty() => {
+				return Ok(None);
+			}
+			None => {
+				return Err(PacketReadError::CantRead);
+			}
+		}
+	}
+}
+
+impl Encoder<ApplicationPacket> for ApplicationPacketCodec {
+	type Error = std::io::Error;
+
+	/// Writes the packet with `buffer_size` set to the real size of the body
+	fn encode(&mut self, item: ApplicationPacket, dst: &mut BytesMut) -> Result<(), Self::Error> {
+		let mut bytes = item.to_bytes()?;
+		let body_size = (bytes.len() - HEADER_SIZE) as u32;
+		bytes[16..20].copy_from_slice(&body_size.to_le_bytes());
+		dst.extend_from_slice(&bytes);
+		return Ok(());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use rocket::futures::{SinkExt, StreamExt};
+	use rocket::tokio::io::{duplex, AsyncWriteExt};
+
+	use crate::device_connector::packets::{EmptyPacket, UnregisterDevicePacket};
+	use super::*;
+
+	#[test]
+	fn decode_partial_packets() {
+		let mut codec = ApplicationPacketCodec::new(64);
+		let mut buffer = BytesMut::from(&[
+			0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+			1, 0, 0, 0,
+			0b0_0000010,
+		][..]);
+		assert!(matches!(codec.decode(&mut buffer), Ok(None)));
+		buffer.extend_from_slice(&[1]);
+		let packet = codec.decode(&mut buffer).unwrap().unwrap();
+		assert!(matches!(packet.message, Message::UnregisterDevice(UnregisterDevicePacket { success: 1 })));
+		assert!(buffer.is_empty());
+	}
+
+	#[test]
+	fn reject_oversized_packet() {
+		let mut codec = ApplicationPacketCodec::new(64);
+		let mut buffer = BytesMut::from(&[
+			0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+			0, 0, 0, 1,
+			0b0_0000001,
+		][..]);
+		assert!(matches!(codec.decode(&mut buffer), Err(PacketReadError::Oversized(0x01000000))));
+	}
+
+	#[test]
+	fn skip_unknown_and_invalid_packets() {
+		let mut codec = ApplicationPacketCodec::new(64);
+		let mut buffer = BytesMut::from(&[
+			// Unknown message 0x7F with 2 bytes of body
+			0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+			2, 0, 0, 0,
+			0b0_1111111,
+			1, 2,
+			// Registration with a body that is too short
+			0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+			1, 0, 0, 0,
+			0b0_0000001,
+			1,
+			// Valid heartbeat
+			0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+			0, 0, 0, 0,
+			0b0_0000000,
+		][..]);
+		let packet = codec.decode(&mut buffer).unwrap().unwrap();
+		assert!(matches!(packet.message, Message::NoOperation(_)));
+		assert!(buffer.is_empty());
+	}
+
+	#[rocket::async_test]
+	async fn framed_round_trip() {
+		let (client, server) = duplex(1024);
+		let mut client = ApplicationPacketCodec::new(64).framed(client);
+		let mut server = ApplicationPacketCodec::new(64).framed(server);
+
+		client.send(ApplicationPacket {
+			header: PacketHeader {
+				session_id: [7; 16],
+				// Corrected by the encoder
+				buffer_size: 100,
+				is_response: false,
+			},
+			message: Message::NoOperation(EmptyPacket {}),
+		}).await.unwrap();
+		let received = server.next().await.unwrap().unwrap();
+		assert_eq!(received.header.session_id, [7; 16]);
+		assert_eq!(received.header.buffer_size, 0);
+		assert!(matches!(received.message, Message::NoOperation(_)));
+
+		// Stream ending in the middle of a packet
+		let mut client = client.into_inner();
+		client.write_all(&[0; 10]).await.unwrap();
+		drop(client);
+		assert!(matches!(server.next().await, Some(Err(PacketReadError::CantRead))));
+	}
+}
@@ -0,0 +1,221 @@
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+use chrono::{DateTime, Utc};
+
+use super::packets::{ImageChunk, ImageChunkType};
+
+/// Maximum amount of chunks kept for a single device while waiting for a frame to complete
+const MAX_PENDING_CHUNKS: usize = 4096;
+
+/// Complete image reassembled from chunks sent by a device
+#[derive(Debug, Clone)]
+pub struct Frame {
+	pub device_id: [u8; 16],
+	/// Number of the frame among all frames received from the device, starting at 0.
+	/// Given when the first chunk of the frame arrives, so frames that lose other chunks leave gaps
+	pub sequence: u64,
+	pub received_at: DateTime<Utc>,
+	pub data: Vec<u8>,
+}
+
+struct PendingChunk {
+	chunk_type: ImageChunkType,
+	/// Number of the frame, set on its first chunk
+	sequence: Option<u64>,
+	image_bytes: Vec<u8>,
+	received_at: Instant,
+}
+
+#[derive(Default)]
+struct DeviceChunks {
+	next_sequence: u64,
+	chunks: HashMap<u32, PendingChunk>,
+}
+
+impl DeviceChunks {
+	fn take_sequence(&mut self) -> u64 {
+		let sequence = self.next_sequence;
+		self.next_sequence += 1;
+		return sequence;
+	}
+
+	/// Stores the chunk and returns the number and image data of the frame if it completed one
+	fn push(&mut self, chunk: ImageChunk, now: Instant) -> Option<(u64, Vec<u8>)> {
+		if chunk.chunk_type == ImageChunkType::Only {
+			return Some((self.take_sequence(), chunk.image_bytes));
+		}
+		if self.chunks.len() >= MAX_PENDING_CHUNKS {
+			log::warn!("Too many pending chunks, dropping all incomplete frames");
+			self.chunks.clear();
+		}
+		let chunk_id = chunk.chunk_id;
+		let sequence = match self.chunks.get(&chunk_id) {
+			// Repeated datagram
+			Some(pending) if pending.chunk_type == chunk.chunk_type => pending.sequence,
+			_ if chunk.chunk_type == ImageChunkType::First => Some(self.take_sequence()),
+			_ => None,
+		};
+		self.chunks.insert(chunk_id, PendingChunk {
+			chunk_type: chunk.chunk_type,
+			sequence,
+			image_bytes: chunk.image_bytes,
+			received_at: now,
+		});
+
+		let first_id = self.find_boundary(chunk_id, ImageChunkType::First, u32::wrapping_sub)?;
+		let last_id = self.find_boundary(chunk_id, ImageChunkType::Last, u32::wrapping_add)?;
+		let sequence = self.chunks[&first_id].sequence.unwrap();
+		let mut data = Vec::new();
+		let mut current_id = first_id;
+		loop {
+			let chunk = self.chunks.remove(&current_id).unwrap();
+			data.extend_from_slice(&chunk.image_bytes);
+			if current_id == last_id {
+				break;
+			}
+			current_id = current_id.wrapping_add(1);
+		}
+		return Some((sequence, data));
+	}
+
+	/// Walks from `start_id` using `step` over consecutive middle chunks until a chunk of `boundary_type` is found.
+	/// Returns `None` if a chunk is missing or belongs to another frame
+	fn find_boundary(&self, start_id: u32, boundary_type: ImageChunkType, step: fn(u32, u32) -> u32) -> Option<u32> {
+		let mut current_id = start_id;
+		for _ in 0..self.chunks.len() {
+			let chunk = self.chunks.get(&current_id)?;
+			if chunk.chunk_type == boundary_type {
+				return Some(current_id);
+			}
+			if current_id != start_id && chunk.chunk_type != ImageChunkType::Middle {
+				return None;
+			}
+			current_id = step(current_id, 1);
+		}
+		return None;
+	}
+}
+
+/// Reassembles image chunks received over UDP into complete frames, separately for every device
+pub struct FrameReassembler {
+	timeout: Duration,
+	devices: HashMap<[u8; 16], DeviceChunks>,
+}
+
+impl FrameReassembler {
+	/// `timeout` is the time after which chunks of an incomplete frame are dropped
+	pub fn new(timeout: Duration) -> Self {
+		return Self {
+			timeout,
+			devices: HashMap::new(),
+		};
+	}
+
+	/// Adds a chunk received from a device. Returns a frame if the chunk completed one
+	pub fn push(&mut self, device_id: [u8; 16], chunk: ImageChunk, now: Instant) -> Option<Frame> {
+		let (sequence, data) = self.devices.entry(device_id).or_default().push(chunk, now)?;
+		return Some(Frame {
+			device_id,
+			sequence,
+			received_at: Utc::now(),
+			data,
+		});
+	}
+
+	/// Drops chunks of frames that did not complete within the timeout
+	pub fn drop_expired(&mut self, now: Instant) {
+		for (device_id, device) in self.devices.iter_mut() {
+			let pending_count = device.chunks.len();
+			device.chunks.retain(|_, chunk| now.duration_since(chunk.received_at) < self.timeout);
+			if device.chunks.len() != pending_count {
+				log::debug!("Dropped {} expired chunks of device {:?}", pending_count - device.chunks.len(), device_id);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const DEVICE_ID: [u8; 16] = [1; 16];
+
+	fn chunk(chunk_id: u32, chunk_type: ImageChunkType, image_bytes: &[u8]) -> ImageChunk {
+		return ImageChunk {
+			chunk_id,
+			chunk_type,
+			session_id: [0; 16],
+			image_bytes: Vec::from(image_bytes),
+		};
+	}
+
+	#[test]
+	fn only_chunk_is_a_frame() {
+		let mut reassembler = FrameReassembler::new(Duration::from_secs(1));
+		let frame = reassembler.push(DEVICE_ID, chunk(7, ImageChunkType::Only, &[1, 2, 3]), Instant::now()).unwrap();
+		assert_eq!(frame.data, vec![1, 2, 3]);
+		assert_eq!(frame.device_id, DEVICE_ID);
+		assert_eq!(frame.sequence, 0);
+	}
+
+	#[test]
+	fn reassembles_out_of_order_chunks() {
+		let mut reassembler = FrameReassembler::new(Duration::from_secs(1));
+		let now = Instant::now();
+		assert!(reassembler.push(DEVICE_ID, chunk(12, ImageChunkType::Last, &[4]), now).is_none());
+		assert!(reassembler.push(DEVICE_ID, chunk(10, ImageChunkType::First, &[1]), now).is_none());
+		let frame = reassembler.push(DEVICE_ID, chunk(11, ImageChunkType::Middle, &[2, 3]), now).unwrap();
+		assert_eq!(frame.data, vec![1, 2, 3, 4]);
+
+		let frame = reassembler.push(DEVICE_ID, chunk(13, ImageChunkType::Only, &[5]), now).unwrap();
+		assert_eq!(frame.sequence, 1);
+	}
+
+	#[test]
+	fn chunk_ids_wrap_around() {
+		let mut reassembler = FrameReassembler::new(Duration::from_secs(1));
+		let now = Instant::now();
+		assert!(reassembler.push(DEVICE_ID, chunk(u32::MAX, ImageChunkType::First, &[1]), now).is_none());
+		let frame = reassembler.push(DEVICE_ID, chunk(0, ImageChunkType::Last, &[2]), now).unwrap();
+		assert_eq!(frame.data, vec![1, 2]);
+	}
+
+	#[test]
+	fn does_not_mix_frames() {
+		let mut reassembler = FrameReassembler::new(Duration::from_secs(1));
+		let now = Instant::now();
+		// Frame 1 misses its middle chunk, frame 2 starts right after it
+		assert!(reassembler.push(DEVICE_ID, chunk(1, ImageChunkType::First, &[1]), now).is_none());
+		assert!(reassembler.push(DEVICE_ID, chunk(3, ImageChunkType::Last, &[3]), now).is_none());
+		assert!(reassembler.push(DEVICE_ID, chunk(5, ImageChunkType::Last, &[5]), now).is_none());
+		let frame = reassembler.push(DEVICE_ID, chunk(4, ImageChunkType::First, &[4]), now).unwrap();
+		assert_eq!(frame.data, vec![4, 5]);
+	}
+
+	#[test]
+	fn frames_with_lost_chunks_leave_gaps() {
+		let mut reassembler = FrameReassembler::new(Duration::from_secs(1));
+		let now = Instant::now();
+		let frame = reassembler.push(DEVICE_ID, chunk(1, ImageChunkType::Only, &[1]), now).unwrap();
+		assert_eq!(frame.sequence, 0);
+		// Chunk 3 is lost, so the frame in chunks 2-4 never completes
+		assert!(reassembler.push(DEVICE_ID, chunk(2, ImageChunkType::First, &[2]), now).is_none());
+		assert!(reassembler.push(DEVICE_ID, chunk(4, ImageChunkType::Last, &[4]), now).is_none());
+		reassembler.drop_expired(now + Duration::from_secs(2));
+		assert!(reassembler.push(DEVICE_ID, chunk(5, ImageChunkType::First, &[5]), now).is_none());
+		// Repeated datagrams don't take another number
+		assert!(reassembler.push(DEVICE_ID, chunk(5, ImageChunkType::First, &[5]), now).is_none());
+		let frame = reassembler.push(DEVICE_ID, chunk(6, ImageChunkType::Last, &[6]), now).unwrap();
+		assert_eq!(frame.data, vec![5, 6]);
+		assert_eq!(frame.sequence, 2);
+	}
+
+	#[test]
+	fn drops_expired_chunks() {
+		let mut reassembler = FrameReassembler::new(Duration::from_secs(1));
+		let now = Instant::now();
+		assert!(reassembler.push(DEVICE_ID, chunk(1, ImageChunkType::First, &[1]), now).is_none());
+		reassembler.drop_expired(now + Duration::from_secs(2));
+		assert!(reassembler.push(DEVICE_ID, chunk(2, ImageChunkType::Last, &[2]), now + Duration::from_secs(2)).is_none());
+	}
+}
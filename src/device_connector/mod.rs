@@ -1,60 +1,241 @@
-use std::{collections::HashMap, sync::{Arc, Mutex}};
+use std::{collections::HashMap, fmt::Display, net::{IpAddr, Ipv4Addr, SocketAddr}, path::{Path, PathBuf}, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}, time::{Duration, Instant}};
 
-use deku::{DekuContainerWrite, DekuWriter};
-use diesel::{insert_into, result::{DatabaseErrorKind, Error}, update, ExpressionMethods, QueryDsl, RunQueryDsl};
-use packets::{ApplicationPacket, InitiateConnectionPacket, PacketHeader, PacketReadError, RegisterDevicePacket, UnregisterDevicePacket};
+use chrono::{DateTime, Utc};
+use diesel::{result::{DatabaseErrorKind, Error}, update, ExpressionMethods, QueryDsl, RunQueryDsl};
+use frames::{Frame, FrameReassembler};
+use packets::{ApplicationPacket, ImageChunk, InitiateConnectionPacket, PacketHeader, PacketReadError, RegisterDevicePacket, UnregisterDevicePacket};
 use rand::Rng;
-use rocket::{fairing::{Fairing, Info, Kind}, tokio::{io::AsyncWriteExt, net::{TcpListener, TcpStream, UdpSocket}, select, spawn, task::JoinHandle}, Build, Rocket};
-use tokio_util::sync::CancellationToken;
+use codec::{ApplicationPacketCodec, PacketStream};
+use store::DeviceStore;
+use rocket::{fairing::{Fairing, Info, Kind}, futures::{SinkExt, StreamExt}, tokio::{io::{AsyncRead, AsyncWrite}, net::{TcpListener, UdpSocket}, select, spawn, sync::{broadcast::{self, error::RecvError}, mpsc}, task::JoinHandle, time::{interval, sleep, timeout}, pin}, Build, Orbit, Rocket};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Socket, Type};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
-use crate::{model::{Device, User}, MainDatabase};
+use crate::{model::{Device, DeviceApproval, Pairing}, MainDatabase};
 use crate::schema::device::dsl as device_dsl;
-use crate::schema::users::dsl as users_dsl;
 
+pub mod codec;
+pub mod frames;
 pub mod packets;
+pub mod storage;
+pub mod store;
 
-type SessionList = Arc<Mutex<HashMap<[u8; 16], Session>>>;
+/// Largest possible payload of a UDP datagram
+const MAX_DATAGRAM_SIZE: usize = 65507;
+/// Time after which chunks of an incomplete frame are dropped
+const FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+/// Amount of frames buffered for slow frame receivers
+const FRAME_CHANNEL_CAPACITY: usize = 64;
+/// Longest time between checks for abandoned registrations
+const REGISTRATION_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
 
-pub struct Session;
+pub(crate) type SessionList = Arc<Mutex<HashMap<[u8; 16], Session>>>;
+
+/// Live connection of a device that completed the `InitiateConnection` handshake
+#[derive(Debug, Clone)]
+pub struct Session {
+	pub device_id: [u8; 16],
+	/// `user_id` of the device owner
+	pub owner_id: [u8; 16],
+	/// Address of the TCP peer that initiated the session
+	pub address: SocketAddr,
+	pub connected_at: DateTime<Utc>,
+	/// Time of the last packet received over TCP
+	pub last_seen: DateTime<Utc>,
+	/// Packets sent here are written to the device connection
+	pub outgoing: mpsc::UnboundedSender<ApplicationPacket>,
+	/// Cancelling closes the device connection, which removes the session
+	pub closer: CancellationToken,
+}
+
+/// Device bridge settings, read from the `device_bridge` table of the Rocket configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DeviceBridgeConfig {
+	/// Address the TCP listener for device connections is bound to
+	pub tcp_address: IpAddr,
+	/// Port of the TCP listener, 0 picks a free port. See [`DeviceBridge::tcp_address`]
+	pub tcp_port: u16,
+	/// Address the UDP socket receiving image chunks is bound to
+	pub udp_address: IpAddr,
+	/// Port of the UDP socket, 0 picks a free port. See [`DeviceBridge::udp_address`]
+	pub udp_port: u16,
+	/// Makes sockets bound to IPv6 addresses refuse IPv4 traffic. When unset `::` accepts both
+	pub ipv6_only: bool,
+	/// Directory under which received frames are stored
+	pub storage_root: PathBuf,
+	/// Expected time between heartbeats of a device, in seconds
+	pub heartbeat_interval: u64,
+	/// Amount of heartbeats a device can miss before its session expires
+	pub missed_heartbeats: u32,
+	/// Largest accepted body of a TCP packet, in bytes. Devices sending bigger packets are disconnected
+	pub max_packet_body_size: u32,
+	/// Time the bridge tasks get to finish during shutdown, in seconds
+	pub shutdown_timeout: u64,
+	/// Time a device has to finish the second registration stage, in seconds. Unfinished registrations and rejected devices are removed afterwards
+	pub registration_timeout: u64,
+	/// Time a device has to start a session after connecting, in seconds. Connections without a session are closed afterwards
+	pub handshake_timeout: u64,
+}
+
+impl DeviceBridgeConfig {
+	/// Time without heartbeats after which a session expires
+	pub fn session_timeout(&self) -> Duration {
+		return Duration::from_secs(self.heartbeat_interval) * self.missed_heartbeats;
+	}
+
+	pub fn registration_timeout(&self) -> Duration {
+		return Duration::from_secs(self.registration_timeout);
+	}
+
+	pub fn handshake_timeout(&self) -> Duration {
+		return Duration::from_secs(self.handshake_timeout);
+	}
+}
+
+impl Default for DeviceBridgeConfig {
+	fn default() -> Self {
+		return Self {
+			tcp_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+			tcp_port: 3333,
+			udp_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+			udp_port: 3333,
+			ipv6_only: false,
+			storage_root: PathBuf::from("recordings"),
+			heartbeat_interval: 30,
+			missed_heartbeats: 3,
+			max_packet_body_size: 256,
+			shutdown_timeout: 5,
+			registration_timeout: 600,
+			handshake_timeout: 10,
+		};
+	}
+}
+
+/// Failure to start the device bridge
+#[derive(Debug)]
+pub enum BridgeStartError {
+	TcpBind(SocketAddr, std::io::Error),
+	UdpBind(SocketAddr, std::io::Error),
+}
+
+impl Display for BridgeStartError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			BridgeStartError::TcpBind(address, err) => write!(f, "couldn't listen for device connections on TCP {}: {}", address, err),
+			BridgeStartError::UdpBind(address, err) => write!(f, "couldn't receive image chunks on UDP {}: {}", address, err),
+		}
+	}
+}
+
+/// Creates a non-blocking socket bound to `address`, listening if it's a stream socket.
+/// IPv6 sockets also accept IPv4 traffic unless `ipv6_only` is set
+fn bind_socket(address: SocketAddr, socket_type: Type, ipv6_only: bool) -> std::io::Result<Socket> {
+	let socket = Socket::new(Domain::for_address(address), socket_type, None)?;
+	if address.is_ipv6() {
+		socket.set_only_v6(ipv6_only)?;
+	}
+	if socket_type == Type::STREAM {
+		// Same as std, so the port can be reused right after a restart
+		socket.set_reuse_address(true)?;
+	}
+	socket.set_nonblocking(true)?;
+	socket.bind(&address.into())?;
+	if socket_type == Type::STREAM {
+		socket.listen(1024)?;
+	}
+	return Ok(socket);
+}
 
 pub struct DeviceBridge {
-	tcp_listening_task: Option<JoinHandle<()>>,
-	udp_socket_task: Option<JoinHandle<()>>,
-	port: u16,
+	// Taken out and awaited by `shutdown`, which only gets a shared reference from Rocket
+	tcp_listening_task: Mutex<Option<JoinHandle<()>>>,
+	udp_socket_task: Mutex<Option<JoinHandle<()>>>,
+	storage_task: Mutex<Option<JoinHandle<()>>>,
+	session_expiry_task: Mutex<Option<JoinHandle<()>>>,
+	registration_sweeper_task: Mutex<Option<JoinHandle<()>>>,
+	/// Handlers of device connections, awaited by `shutdown` so they can say goodbye and save the last seen times
+	connection_tasks: TaskTracker,
+	/// Address the TCP listener is actually bound to, with the real port if 0 was configured
+	tcp_address: SocketAddr,
+	/// Address the UDP socket is actually bound to
+	udp_address: SocketAddr,
+	config: DeviceBridgeConfig,
 	sessions: SessionList,
+	/// Every frame reassembled from chunks received over UDP
+	frames: broadcast::Sender<Arc<Frame>>,
 	canceller: CancellationToken,
+	/// Stops the storage task. Separate from `canceller`, so frames received before the UDP task stopped still get stored
+	storage_canceller: CancellationToken,
 	database: Arc<MainDatabase>,
+	started_at: DateTime<Utc>,
+	/// Frames reassembled since the bridge started
+	frames_received: Arc<AtomicU64>,
+}
+
+/// Overview of the bridge for administrators
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BridgeStats {
+	pub started_at: DateTime<Utc>,
+	pub tcp_address: SocketAddr,
+	pub udp_address: SocketAddr,
+	pub connected_devices: usize,
+	/// Open live streams of all devices
+	pub live_viewers: usize,
+	pub frames_received: u64,
 }
 
 impl DeviceBridge {
-	pub fn new(port: u16, database: MainDatabase) -> Self {
+	pub fn new(config: DeviceBridgeConfig, database: MainDatabase) -> Result<Self, BridgeStartError> {
 		let sessions = Arc::new(Mutex::new(HashMap::new()));
 
 		let mut result = Self {
-			tcp_listening_task: None,
-			udp_socket_task: None,
-			port,
+			tcp_listening_task: Mutex::new(None),
+			udp_socket_task: Mutex::new(None),
+			storage_task: Mutex::new(None),
+			session_expiry_task: Mutex::new(None),
+			registration_sweeper_task: Mutex::new(None),
+			connection_tasks: TaskTracker::new(),
+			tcp_address: SocketAddr::new(config.tcp_address, config.tcp_port),
+			udp_address: SocketAddr::new(config.udp_address, config.udp_port),
+			config,
 			sessions,
+			frames: broadcast::channel(FRAME_CHANNEL_CAPACITY).0,
 			canceller: CancellationToken::new(),
+			storage_canceller: CancellationToken::new(),
 			database: Arc::new(database),
+			started_at: Utc::now(),
+			frames_received: Arc::new(AtomicU64::new(0)),
 		};
-		result.init();
+		result.init()?;
 
-		return result;
+		return Ok(result);
 	}
 
-	pub fn init(&mut self) {
-		let tcp_socket = std::net::TcpListener::bind(("0.0.0.0", self.port)).unwrap();
-		tcp_socket.set_nonblocking(true).unwrap();
-		let tcp_socket = TcpListener::from_std(tcp_socket).unwrap();
+	/// Binds the sockets and starts the bridge tasks
+	pub fn init(&mut self) -> Result<(), BridgeStartError> {
+		let tcp_address = self.tcp_address;
+		let tcp_socket = bind_socket(tcp_address, Type::STREAM, self.config.ipv6_only)
+			.and_then(|socket| TcpListener::from_std(socket.into()))
+			.map_err(|err| BridgeStartError::TcpBind(tcp_address, err))?;
+		self.tcp_address = tcp_socket.local_addr().map_err(|err| BridgeStartError::TcpBind(tcp_address, err))?;
 
-		let udp_listener = std::net::UdpSocket::bind(("0.0.0.0", self.port)).unwrap();
-		udp_listener.set_nonblocking(true).unwrap();
-		let udp_listener = UdpSocket::from_std(udp_listener).unwrap();
+		let udp_address = self.udp_address;
+		let udp_listener = bind_socket(udp_address, Type::DGRAM, self.config.ipv6_only)
+			.and_then(|socket| UdpSocket::from_std(socket.into()))
+			.map_err(|err| BridgeStartError::UdpBind(udp_address, err))?;
+		self.udp_address = udp_listener.local_addr().map_err(|err| BridgeStartError::UdpBind(udp_address, err))?;
 
 		let session_clone = self.sessions.clone();
 		let canceller = self.canceller.clone();
 		let db_clone = self.database.clone();
+		let limits = ConnectionLimits {
+			max_body_size: self.config.max_packet_body_size,
+			handshake_timeout: self.config.handshake_timeout(),
+		};
+		let storage_root = self.config.storage_root.clone();
+		let connection_tasks = self.connection_tasks.clone();
 		let tcp_listening_task = spawn(async move {
 			loop {
 				select! {
@@ -62,39 +243,434 @@ impl DeviceBridge {
 						return;
 					}
 					connection = tcp_socket.accept() => {
-						let (stream, _) = connection.unwrap();
-						let db_clone = db_clone.clone();
-						spawn(handle_connection(stream, session_clone.clone(), canceller.clone(), db_clone));
+						match connection {
+							Ok((stream, address)) => {
+								let db_clone = db_clone.clone();
+								connection_tasks.spawn(handle_connection(stream, address, limits, storage_root.clone(), session_clone.clone(), canceller.clone(), db_clone));
+							}
+							Err(err) => {
+								log::warn!("Couldn't accept device connection: {}", err);
+							}
+						}
 					}
 				}
 			}
 		});
 
+		let session_clone = self.sessions.clone();
+		let canceller = self.canceller.clone();
+		let frames = self.frames.clone();
+		let frames_received = self.frames_received.clone();
 		let udp_socket_task = spawn(async move {
-			let mut buffer = Vec::new();
-			let packet_addr = udp_listener.recv_from(&mut buffer).await.unwrap().1;
-			println!("packet from {}", packet_addr);
+			let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+			let mut reassembler = FrameReassembler::new(FRAME_TIMEOUT);
+			let mut expiry_interval = interval(FRAME_TIMEOUT);
+			loop {
+				select! {
+					_ = canceller.cancelled() => {
+						return;
+					}
+					_ = expiry_interval.tick() => {
+						reassembler.drop_expired(Instant::now());
+					}
+					received = udp_listener.recv_from(&mut buffer) => {
+						match received {
+							Ok((size, address)) => {
+								if handle_datagram(&buffer[..size], address, &session_clone, &mut reassembler, &frames) {
+									frames_received.fetch_add(1, Ordering::Relaxed);
+								}
+							}
+							Err(err) => {
+								log::warn!("Error while receiving UDP datagram: {:?}", err);
+							}
+						}
+					}
+				}
+			}
+		});
+
+		let mut frame_receiver = self.frames.subscribe();
+		let canceller = self.storage_canceller.clone();
+		let db_clone = self.database.clone();
+		let storage_root = self.config.storage_root.clone();
+		let storage_task = spawn(async move {
+			loop {
+				select! {
+					_ = canceller.cancelled() => {
+						// Store frames that were already received, so they aren't lost on shutdown
+						while let Ok(frame) = frame_receiver.try_recv() {
+							if let Err(err) = storage::store_frame(&storage_root, &frame, &db_clone).await {
+								log::error!("Failed to store frame {} of device {:?}: {}", frame.sequence, frame.device_id, err);
+							}
+						}
+						return;
+					}
+					received = frame_receiver.recv() => {
+						match received {
+							Ok(frame) => {
+								if let Err(err) = storage::store_frame(&storage_root, &frame, &db_clone).await {
+									log::error!("Failed to store frame {} of device {:?}: {}", frame.sequence, frame.device_id, err);
+								}
+							}
+							Err(RecvError::Lagged(count)) => {
+								log::warn!("Frame storage is lagging behind, {} frames were not stored", count);
+							}
+							Err(RecvError::Closed) => {
+								return;
+							}
+						}
+					}
+				}
+			}
+		});
+
+		let session_clone = self.sessions.clone();
+		let canceller = self.canceller.clone();
+		let session_timeout = self.config.session_timeout();
+		let mut expiry_interval = interval(Duration::from_secs(self.config.heartbeat_interval.max(1)));
+		let session_expiry_task = spawn(async move {
+			loop {
+				select! {
+					_ = canceller.cancelled() => {
+						return;
+					}
+					_ = expiry_interval.tick() => {
+						expire_sessions(&session_clone, session_timeout);
+					}
+				}
+			}
+		});
+
+		let canceller = self.canceller.clone();
+		let db_clone = self.database.clone();
+		let registration_timeout = self.config.registration_timeout();
+		let storage_root = self.config.storage_root.clone();
+		let mut sweep_interval = interval(registration_timeout.clamp(Duration::from_secs(1), REGISTRATION_SWEEP_INTERVAL));
+		let registration_sweeper_task = spawn(async move {
+			loop {
+				select! {
+					_ = canceller.cancelled() => {
+						return;
+					}
+					_ = sweep_interval.tick() => {
+						sweep_registrations(db_clone.as_ref(), &storage_root, registration_timeout).await;
+					}
+				}
+			}
 		});
 
-		self.tcp_listening_task = Some(tcp_listening_task);
-		self.udp_socket_task = Some(udp_socket_task);
+		*self.tcp_listening_task.get_mut().unwrap() = Some(tcp_listening_task);
+		*self.udp_socket_task.get_mut().unwrap() = Some(udp_socket_task);
+		*self.storage_task.get_mut().unwrap() = Some(storage_task);
+		*self.session_expiry_task.get_mut().unwrap() = Some(session_expiry_task);
+		*self.registration_sweeper_task.get_mut().unwrap() = Some(registration_sweeper_task);
+		return Ok(());
+	}
+
+	/// Tells connected devices that the server is going away, stops accepting connections and datagrams,
+	/// stores frames that were already received and waits for the bridge tasks and connection handlers to finish.
+	/// Tasks that don't finish within the configured timeout are aborted
+	pub async fn shutdown(&self) {
+		let goodbye_count = self.disconnect_all();
+		log::info!("Shutting down device bridge, notified {} connected devices", goodbye_count);
+		self.canceller.cancel();
+
+		let shutdown_timeout = Duration::from_secs(self.config.shutdown_timeout);
+		Self::finish_task("TCP listener", &self.tcp_listening_task, shutdown_timeout).await;
+		// No new connections are accepted after the listener finished
+		self.connection_tasks.close();
+		if timeout(shutdown_timeout, self.connection_tasks.wait()).await.is_err() {
+			log::warn!("{} device connection handlers didn't finish within {:?}", self.connection_tasks.len(), shutdown_timeout);
+		}
+		Self::finish_task("UDP socket", &self.udp_socket_task, shutdown_timeout).await;
+		Self::finish_task("session expiry", &self.session_expiry_task, shutdown_timeout).await;
+		Self::finish_task("registration sweeper", &self.registration_sweeper_task, shutdown_timeout).await;
+		// Started only after the UDP task finished, so no new frames can arrive
+		self.storage_canceller.cancel();
+		Self::finish_task("frame storage", &self.storage_task, shutdown_timeout).await;
+	}
+
+	/// Sends a [`packets::Message::Disconnect`] to every connected device and returns the amount of devices notified.
+	/// The packets are delivered by the connection handlers before they close
+	fn disconnect_all(&self) -> usize {
+		let sessions = self.sessions.lock().unwrap();
+		let mut notified = 0;
+		for (session_id, session) in sessions.iter() {
+			let packet = ApplicationPacket {
+				header: PacketHeader {
+					session_id: *session_id,
+					buffer_size: 0,
+					is_response: false,
+				},
+				message: packets::Message::Disconnect(packets::EmptyPacket {}),
+			};
+			if session.outgoing.send(packet).is_ok() {
+				notified += 1;
+			}
+		}
+		return notified;
+	}
+
+	async fn finish_task(name: &str, task: &Mutex<Option<JoinHandle<()>>>, limit: Duration) {
+		// Already taken if shutdown ran before
+		let Some(mut handle) = task.lock().unwrap().take() else {
+			return;
+		};
+		match timeout(limit, &mut handle).await {
+			Ok(Ok(())) => {
+				log::debug!("Device bridge {} task finished", name);
+			}
+			Ok(Err(err)) => {
+				log::error!("Device bridge {} task failed: {}", name, err);
+			}
+			Err(_) => {
+				log::warn!("Device bridge {} task didn't finish within {:?}, aborting it", name, limit);
+				handle.abort();
+			}
+		}
+	}
+
+	pub fn config(&self) -> &DeviceBridgeConfig {
+		return &self.config;
+	}
+
+	/// Address devices connect to over TCP
+	pub fn tcp_address(&self) -> SocketAddr {
+		return self.tcp_address;
+	}
+
+	/// Address devices send image chunks to over UDP
+	pub fn udp_address(&self) -> SocketAddr {
+		return self.udp_address;
+	}
+
+	/// Receiver of every frame reassembled from now on, from all devices
+	pub fn subscribe_frames(&self) -> broadcast::Receiver<Arc<Frame>> {
+		return self.frames.subscribe();
+	}
+
+	/// Current session of the device, `None` if the device is offline
+	pub fn device_session(&self, device_id: [u8; 16]) -> Option<Session> {
+		return self.sessions.lock().unwrap().values().find(|session| session.device_id == device_id).cloned();
+	}
+
+	/// Changes the owner recorded in the session of the device after its ownership was transferred.
+	/// Returns `false` if the device is not connected
+	pub fn rebind_owner(&self, device_id: [u8; 16], owner_id: [u8; 16]) -> bool {
+		let mut sessions = self.sessions.lock().unwrap();
+		match sessions.values_mut().find(|session| session.device_id == device_id) {
+			Some(session) => {
+				log::info!("Device {:?} at {} now belongs to user {:?}", device_id, session.address, owner_id);
+				session.owner_id = owner_id;
+				return true;
+			}
+			None => {
+				return false;
+			}
+		}
+	}
+
+	/// Sends a [`packets::Message::Disconnect`] to the device and closes its connection. The device can connect again later.
+	/// Returns `false` if the device is not connected
+	pub fn disconnect(&self, device_id: [u8; 16]) -> bool {
+		let sessions = self.sessions.lock().unwrap();
+		let Some((session_id, session)) = sessions.iter().find(|(_, session)| session.device_id == device_id) else {
+			return false;
+		};
+		log::info!("Disconnecting device {:?} at {}", device_id, session.address);
+		let packet = ApplicationPacket {
+			header: PacketHeader {
+				session_id: *session_id,
+				buffer_size: 0,
+				is_response: false,
+			},
+			message: packets::Message::Disconnect(packets::EmptyPacket {}),
+		};
+		let _ = session.outgoing.send(packet);
+		session.closer.cancel();
+		return true;
+	}
+
+	pub fn stats(&self) -> BridgeStats {
+		return BridgeStats {
+			started_at: self.started_at,
+			tcp_address: self.tcp_address,
+			udp_address: self.udp_address,
+			connected_devices: self.sessions.lock().unwrap().len(),
+			// The storage task is always subscribed until shutdown
+			live_viewers: self.frames.receiver_count().saturating_sub(1),
+			frames_received: self.frames_received.load(Ordering::Relaxed),
+		};
+	}
+
+	/// Tells the connected device that it was removed by its owner, ends its session and closes the connection once the packet is written.
+	/// Returns `false` if the device is not connected
+	pub fn notify_unregistered(&self, device_id: [u8; 16]) -> bool {
+		let mut sessions = self.sessions.lock().unwrap();
+		let session_id = match sessions.iter().find(|(_, session)| session.device_id == device_id) {
+			Some((session_id, _)) => *session_id,
+			None => {
+				return false;
+			}
+		};
+		let session = sessions.remove(&session_id).unwrap();
+		log::info!("Notifying device {:?} at {} about its removal", device_id, session.address);
+		let packet = ApplicationPacket {
+			header: PacketHeader {
+				session_id,
+				buffer_size: 1,
+				is_response: false,
+			},
+			message: packets::Message::UnregisterDevice(UnregisterDevicePacket { success: 0 }),
+		};
+		let sent = session.outgoing.send(packet).is_ok();
+		session.closer.cancel();
+		return sent;
+	}
+
+	/// Directory with the stored frames, see [`storage::delete_device`]
+	pub fn storage_root(&self) -> &Path {
+		return &self.config.storage_root;
 	}
 
-	pub fn fairing(port: u16) -> DeviceBridgeFairing {
-		return DeviceBridgeFairing { port };
+	pub fn fairing() -> DeviceBridgeFairing {
+		return DeviceBridgeFairing {};
 	}
 }
 	
-async fn handle_connection(mut socket: TcpStream, sessions: SessionList, canceller: CancellationToken, database: Arc<MainDatabase>) {
+/// Closes connections of devices that did not send anything within `timeout`
+fn expire_sessions(sessions: &SessionList, timeout: Duration) {
+	let now = Utc::now();
+	for session in sessions.lock().unwrap().values() {
+		if (now - session.last_seen).to_std().unwrap_or_default() > timeout {
+			log::info!("Session of device {:?} expired, last seen at {}", session.device_id, session.last_seen);
+			session.closer.cancel();
+		}
+	}
+}
+
+/// Removes devices that didn't finish the second registration stage within `timeout` or were rejected, the same way as devices deleted by their owners
+async fn sweep_registrations<D: DeviceStore>(database: &D, storage_root: &Path, timeout: Duration) {
+	let created_before = Utc::now().naive_utc() - timeout;
+	let abandoned = match database.run(move |conn| Device::abandoned_registrations(conn, created_before)).await {
+		Ok(abandoned) => abandoned,
+		Err(err) => {
+			log::error!("Couldn't find abandoned registrations: {:?}", err);
+			return;
+		}
+	};
+	let mut removed = 0;
+	for device_id in abandoned {
+		let device_id: [u8; 16] = device_id.try_into().unwrap();
+		match storage::delete_device(database, storage_root, device_id).await {
+			Ok(_) => {
+				removed += 1;
+			}
+			// Finished registering or deleted in the meantime
+			Err(Error::NotFound) => {}
+			Err(err) => {
+				log::error!("Couldn't remove abandoned registration of device {:?}: {:?}", device_id, err);
+			}
+		}
+	}
+	if removed > 0 {
+		log::info!("Removed {} devices that didn't finish registration or were rejected", removed);
+	}
+}
+
+/// Limits applied to every device connection
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ConnectionLimits {
+	/// Largest allowed packet body, bigger packets end the connection
+	pub max_body_size: u32,
+	/// Time after which the connection is closed if it has no session
+	pub handshake_timeout: Duration,
+}
+
+/// State of a single TCP connection with a device
+struct Connection {
+	address: SocketAddr,
+	/// Session established on this connection, if any
+	session_id: Option<[u8; 16]>,
+	/// Sender for [`Session::outgoing`]
+	outgoing: mpsc::UnboundedSender<ApplicationPacket>,
+	/// Set by packet handlers when the connection should be closed
+	finished: bool,
+	/// Closes the connection when cancelled
+	closer: CancellationToken,
+	limits: ConnectionLimits,
+	/// Frames of the device are removed from here when it is deleted
+	storage_root: PathBuf,
+}
+
+pub(crate) async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin, D: DeviceStore>(socket: S, address: SocketAddr, limits: ConnectionLimits, storage_root: PathBuf, sessions: SessionList, canceller: CancellationToken, database: Arc<D>) {
+	let (outgoing, outgoing_receiver) = mpsc::unbounded_channel();
+	let mut connection = Connection {
+		address,
+		session_id: None,
+		outgoing,
+		finished: false,
+		closer: canceller.child_token(),
+		limits,
+		storage_root,
+	};
+	let mut stream = ApplicationPacketCodec::new(limits.max_body_size).framed(socket);
+	connection_loop(&mut stream, &mut connection, outgoing_receiver, sessions.clone(), database.clone()).await;
+	if let Some(session_id) = connection.session_id {
+		let session = sessions.lock().unwrap().remove(&session_id);
+		if let Some(session) = session {
+			log::info!("Device {:?} of user {:?} disconnected from {}, connected since {}", session.device_id, session.owner_id, session.address, session.connected_at);
+			let query = update(device_dsl::device.find(session.device_id)).set(device_dsl::last_seen.eq(session.last_seen.naive_utc()));
+			if let Err(err) = database.run(move |conn| query.execute(conn)).await {
+				log::error!("Couldn't save last seen time of device {:?}: {:?}", session.device_id, err);
+			}
+		}
+	}
+}
+
+/// Reads and handles packets and writes packets sent by other tasks until the connection ends
+async fn connection_loop<S: AsyncRead + AsyncWrite + Unpin, D: DeviceStore>(stream: &mut PacketStream<S>, connection: &mut Connection, mut outgoing_receiver: mpsc::UnboundedReceiver<ApplicationPacket>, sessions: SessionList, database: Arc<D>) {
+	let closer = connection.closer.clone();
+	let handshake_deadline = sleep(connection.limits.handshake_timeout);
+	pin!(handshake_deadline);
 	loop {
 		select! {
-			_ = canceller.cancelled() => {
+			_ = &mut handshake_deadline, if connection.session_id.is_none() => {
+				log::info!("{} didn't start a session within {:?}. Closing the connection.", connection.address, connection.limits.handshake_timeout);
 				return;
 			}
-			read_result = packets::read_packet_async(&mut socket) => {
+			_ = closer.cancelled() => {
+				// Deliver packets queued before closing, like the shutdown notice
+				while let Ok(packet) = outgoing_receiver.try_recv() {
+					if let Err(err) = stream.feed(packet).await {
+						log::warn!("Couldn't write packet to {} while closing: {:?}", connection.address, err);
+						return;
+					}
+				}
+				if let Err(err) = stream.flush().await {
+					log::warn!("Couldn't write packets to {} while closing: {:?}", connection.address, err);
+				}
+				return;
+			}
+			// The receiver can't close, since the connection holds a sender
+			Some(packet) = outgoing_receiver.recv() => {
+				if let Err(err) = stream.send(packet).await {
+					log::warn!("Couldn't write packet to {}: {:?}. Ending handler.", connection.address, err);
+					return;
+				}
+			}
+			read_result = stream.next() => {
 				match read_result {
-					Ok(packet) => {
-						match handle_packet(packet, &mut socket, sessions.clone(), &database).await {
+					None => {
+						log::info!("Connection from {} closed", connection.address);
+						return;
+					}
+					Some(Ok(packet)) => {
+						match handle_packet(packet, stream, connection, sessions.clone(), database.as_ref()).await {
+							Ok(_) if connection.finished => {
+								log::debug!("Packet handler finished the connection");
+								return;
+							}
 							Ok(_) => {
 								log::debug!("Finished packet handler");
 							}
@@ -107,12 +683,16 @@ async fn handle_connection(mut socket: TcpStream, sessions: SessionList, cancell
 							}
 						};
 					},
-					Err(PacketReadError::CantRead) => {
-						log::warn!("Couldn't finish reading packet, TCP stream likely to have ended from the other end. Ending handler.");
+					Some(Err(err)) => {
+						match &err {
+							PacketReadError::Oversized(size) => {
+								log::warn!("{} sent a packet with body of {} bytes, exceeding the limit of {}. Ending handler.", connection.address, size, connection.limits.max_body_size);
+							}
+							_ => {
+								log::warn!("Couldn't read packet from {}: {}. Ending handler.", connection.address, err);
+							}
+						}
 						return;
-					},
-					Err(PacketReadError::HeaderParseError(data) | PacketReadError::PacketParseError(data)) => {
-						log::warn!("Packet parsing failure: {}. Likely to require killing the stream anyway.", data);
 					}
 				}
 			}
@@ -120,17 +700,56 @@ async fn handle_connection(mut socket: TcpStream, sessions: SessionList, cancell
 	}
 }
 
+/// Parses an image chunk, checks it belongs to a live session and feeds it to the reassembler.
+/// Returns whether the chunk completed a frame
+fn handle_datagram(datagram: &[u8], address: SocketAddr, sessions: &SessionList, reassembler: &mut FrameReassembler, frames: &broadcast::Sender<Arc<Frame>>) -> bool {
+	let chunk = match ImageChunk::try_from(datagram) {
+		Ok(chunk) => chunk,
+		Err(err) => {
+			log::warn!("Invalid image chunk from {}: {}", address, err);
+			return false;
+		}
+	};
+	let device_id = match sessions.lock().unwrap().get(&chunk.session_id) {
+		Some(session) if session.address.ip() == address.ip() => session.device_id,
+		Some(_) => {
+			log::warn!("Image chunk for session {:?} sent from foreign address {}", chunk.session_id, address);
+			return false;
+		}
+		None => {
+			log::debug!("Image chunk with unknown session {:?} from {}", chunk.session_id, address);
+			return false;
+		}
+	};
+	match reassembler.push(device_id, chunk, Instant::now()) {
+		Some(frame) => {
+			log::debug!("Received frame {} of device {:?} at {} ({} bytes)", frame.sequence, frame.device_id, frame.received_at, frame.data.len());
+			// Sending only fails when nobody is receiving frames at the moment
+			let _ = frames.send(Arc::new(frame));
+			return true;
+		}
+		None => {
+			return false;
+		}
+	}
+}
+
 /// Makes a decision to either kill the socket or not
 enum PacketHandlerError {
 	Ending,
 	NonEnding,
 }
 
-async fn handle_packet(packet: ApplicationPacket, socket: &mut TcpStream, sessions: SessionList, database: &MainDatabase) -> Result<(), PacketHandlerError> {
+async fn handle_packet<S: AsyncRead + AsyncWrite + Unpin, D: DeviceStore>(packet: ApplicationPacket, stream: &mut PacketStream<S>, connection: &mut Connection, sessions: SessionList, database: &D) -> Result<(), PacketHandlerError> {
 	log::debug!("Got packet: {:?}", packet);
+	if let Some(session_id) = connection.session_id {
+		if let Some(session) = sessions.lock().unwrap().get_mut(&session_id) {
+			session.last_seen = Utc::now();
+		}
+	}
 	match packet.message {
 		packets::Message::RegisterDevice(data) => {
-			match handle_registration(socket, database, data).await {
+			match handle_registration(stream, &sessions, database, data).await {
 				Ok(_) => {
 					log::debug!("Finished registration handler");
 					return Ok(());
@@ -138,90 +757,180 @@ async fn handle_packet(packet: ApplicationPacket, socket: &mut TcpStream, sessio
 				Err(DeviceRegisterError::UnknownCameraID) => {
 					return Err(PacketHandlerError::NonEnding);
 				}
+				Err(DeviceRegisterError::DatabaseError(err)) => {
+					log::error!("Database error in registration handler: {:?}", err);
+					return Err(PacketHandlerError::Ending);
+				}
+				Err(DeviceRegisterError::ConnectionError(err)) => {
+					log::warn!("Couldn't send registration response: {:?}", err);
+					return Err(PacketHandlerError::Ending);
+				}
 				Err(_) => {
 					log::debug!("Bubbling ending registration error");
 					return Err(PacketHandlerError::Ending);
 				}
 			}
 		}
-		packets::Message::InitiateConnection(InitiateConnectionPacket { auth_key, camera_id }) => {
-			//
+		packets::Message::InitiateConnection(data) => {
+			if let Some(previous_session) = connection.session_id.take() {
+				log::warn!("Connection from {} is initiating a new session while already having one", connection.address);
+				sessions.lock().unwrap().remove(&previous_session);
+			}
+			match handle_initiate_connection(stream, connection, &sessions, database, data).await {
+				Ok(new_session_id) => {
+					log::debug!("Finished connection initiation handler");
+					connection.session_id = Some(new_session_id);
+					return Ok(());
+				}
+				Err(DeviceConnectError::DatabaseError(err)) => {
+					log::error!("Database error in connection initiation handler: {:?}", err);
+					return Err(PacketHandlerError::Ending);
+				}
+				Err(DeviceConnectError::ConnectionError(err)) => {
+					log::warn!("Couldn't send connection initiation response: {:?}", err);
+					return Err(PacketHandlerError::Ending);
+				}
+				Err(_) => {
+					log::debug!("Bubbling ending connection initiation error");
+					return Err(PacketHandlerError::Ending);
+				}
+			}
 		}
 		packets::Message::NoOperation(_) => {
-			//
+			if packet.header.is_response {
+				return Ok(());
+			}
+			// Heartbeat. Any packet counts as a sign of life, so `last_seen` is updated for all of them below
+			let response = ApplicationPacket {
+				header: PacketHeader {
+					session_id: connection.session_id.unwrap_or_default(),
+					buffer_size: 0,
+					is_response: true,
+				},
+				message: packets::Message::NoOperation(packets::EmptyPacket {}),
+			};
+			if let Err(err) = stream.send(response).await {
+				log::warn!("Couldn't answer heartbeat of {}: {:?}", connection.address, err);
+				return Err(PacketHandlerError::Ending);
+			}
+		}
+		packets::Message::Disconnect(_) => {
+			log::info!("Device at {} is closing the connection", connection.address);
+			connection.finished = true;
 		}
 		packets::Message::UnregisterDevice(UnregisterDevicePacket { success }) => {
-			//
+			if connection.session_id.is_none() {
+				log::warn!("Connection from {} sent unregister packet without a session", connection.address);
+				return Err(PacketHandlerError::NonEnding);
+			}
+			if packet.header.is_response {
+				// Session was already ended by `DeviceBridge::notify_unregistered`
+				log::info!("Device at {} confirmed its removal with status {}", connection.address, success);
+				connection.finished = true;
+				return Ok(());
+			}
+			match handle_unregistration(stream, connection, &sessions, database).await {
+				Ok(_) => {
+					log::debug!("Finished unregistration handler");
+					return Ok(());
+				}
+				Err(err) => {
+					log::error!("Database error in unregistration handler: {:?}", err);
+					return Err(PacketHandlerError::Ending);
+				}
+			}
 		}
 	}
 	return Ok(());
 }
 
 enum DeviceRegisterError {
-	UserDoesNotExist,
+	InvalidPairingToken,
 	UnknownCameraID,
 	InvalidRegisterAttempt,
 	DatabaseError(Error),
-	OtherError,
+	ConnectionError(std::io::Error),
 }
 
-async fn handle_registration(socket: &mut TcpStream, database: &MainDatabase, register_packet: RegisterDevicePacket) -> Result<(), DeviceRegisterError> {
-	let RegisterDevicePacket { auth_key, camera_id, mac_address, user_id} = register_packet;
+async fn handle_registration<S: AsyncRead + AsyncWrite + Unpin, D: DeviceStore>(stream: &mut PacketStream<S>, sessions: &SessionList, database: &D, register_packet: RegisterDevicePacket) -> Result<(), DeviceRegisterError> {
+	let RegisterDevicePacket { auth_key, camera_id, mac_address, pairing_token } = register_packet;
 
 	if camera_id == [0; 16] {
 		log::info!("Device sent empty camera ID, registering.");
-		let user_query = users_dsl::users.find(user_id);
-		let user = database.run(move |conn| user_query.first::<User>(conn)).await;
-		match user {
-			Ok(_) => {
-				log::debug!("Found user for new device registration: {:?}", user_id);
-				let mut new_device = Device {
-					auth_key: Vec::from(auth_key), 
-					device_id: Vec::from(camera_id),
-					mac_address: Vec::from(mac_address),
-					registration_first_stage: true,
-					user_id: Vec::from(user_id),
-				};
-				rand::thread_rng().fill(new_device.device_id.as_mut_slice());
-				loop {
-					let insert_query = insert_into(device_dsl::device).values(new_device.clone());
-					match database.run(|conn| insert_query.execute(conn)).await {
-						Ok(_) => {
-							log::info!("First stage registered new device");
-							let response = ApplicationPacket {
-								header: packets::PacketHeader {
-									session_id: [0; 16],
-									buffer_size: 54,
-									is_response: true
-								},
-								message: packets::Message::RegisterDevice(RegisterDevicePacket {
-									auth_key,
-									camera_id: new_device.device_id.try_into().unwrap(),
-									mac_address,
-									user_id,
-								})
-							};
-							socket.write_all(&response.to_bytes().unwrap()).await.unwrap();
-							return Ok(());
+		let mut new_device = Device {
+			auth_key: Vec::from(auth_key),
+			device_id: Vec::from(camera_id),
+			mac_address: Vec::from(mac_address),
+			registration_first_stage: true,
+			// Set to the owner of the pairing token
+			user_id: Vec::new(),
+			last_seen: None,
+			name: None,
+			location: None,
+			timezone: None,
+			approval: DeviceApproval::Pending,
+			created_at: Utc::now().naive_utc(),
+			mac_conflict: false,
+			organization_id: None,
+			registration_token: None,
+		};
+		rand::thread_rng().fill(new_device.device_id.as_mut_slice());
+		loop {
+			let device_clone = new_device.clone();
+			let registered = database.run(move |conn| Device::insert_paired(conn, Vec::from(pairing_token), device_clone, Utc::now().naive_utc())).await;
+			match registered {
+				Ok(pairing) => {
+					let device = match pairing {
+						Pairing::New(device) => {
+							log::info!("First stage registered new device {:?} of user {:?}", device.device_id, device.user_id);
+							device
 						}
-						Err(Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
-							log::debug!("Device ID {:?} exists in database, regenerating ID", new_device.device_id);
-							rand::thread_rng().fill(new_device.device_id.as_mut_slice());
+						Pairing::Replaced(device) => {
+							log::info!("Device {:?} of user {:?} registered again, probably after a reset", device.device_id, device.user_id);
+							// The old session used the previous auth key
+							sessions.lock().unwrap().retain(|_, session| {
+								if session.device_id.as_slice() == device.device_id.as_slice() {
+									session.closer.cancel();
+									return false;
+								}
+								return true;
+							});
+							device
 						}
-						Err(err) => {
-							log::error!("Error while registering device: {:?}", err);
-							return Err(DeviceRegisterError::DatabaseError(err));
+						Pairing::Conflicting { device, other_owners } => {
+							log::warn!("Device {:?} of user {:?} registered with MAC address {:?}, which belongs to devices of users {:?}. It may be cloned or stolen",
+								device.device_id, device.user_id, device.mac_address, other_owners);
+							device
 						}
-					}
+					};
+					let response = ApplicationPacket {
+						header: packets::PacketHeader {
+							session_id: [0; 16],
+							buffer_size: 54,
+							is_response: true
+						},
+						message: packets::Message::RegisterDevice(RegisterDevicePacket {
+							auth_key,
+							camera_id: device.device_id.try_into().unwrap(),
+							mac_address,
+							pairing_token,
+						})
+					};
+					stream.send(response).await.map_err(DeviceRegisterError::ConnectionError)?;
+					return Ok(());
+				}
+				Err(Error::NotFound) => {
+					log::warn!("Device is trying to register with an invalid or expired pairing token");
+					return Err(DeviceRegisterError::InvalidPairingToken);
+				}
+				Err(Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+					log::debug!("Device ID {:?} exists in database, regenerating ID", new_device.device_id);
+					rand::thread_rng().fill(new_device.device_id.as_mut_slice());
+				}
+				Err(err) => {
+					log::error!("Error while registering device: {:?}", err);
+					return Err(DeviceRegisterError::DatabaseError(err));
 				}
-			}
-			Err(Error::NotFound) => {
-				log::warn!("Device is trying to register with an unknown user id");
-				return Err(DeviceRegisterError::UserDoesNotExist);
-			}
-			Err(err) => {
-				log::warn!("Error while retrieving user in register handler: {:?}", err);
-				return Err(DeviceRegisterError::DatabaseError(err));
 			}
 		}
 	} else {
@@ -230,11 +939,21 @@ async fn handle_registration(socket: &mut TcpStream, database: &MainDatabase, re
 		match dev {
 			Ok(mut device) => {
 				if device.registration_first_stage {
+					// Knowing the ID of the device isn't enough to take over its registration
+					if device.registration_token.as_deref() != Some(pairing_token.as_slice()) {
+						log::warn!("Device {:?} is trying to finish registration with a different pairing token", camera_id);
+						return Err(DeviceRegisterError::InvalidPairingToken);
+					}
 					// get auth key from camera
 					log::info!("Device enters second registration stage.");
 					device.auth_key = Vec::from(auth_key);
-					let query = update(device_dsl::device).set(device);
-					database.run(move |conn| query.execute(conn)).await.unwrap();
+					device.registration_first_stage = false;
+					device.registration_token = None;
+					let query = update(device_dsl::device.find(camera_id)).set(device);
+					if let Err(err) = database.run(move |conn| query.execute(conn)).await {
+						log::error!("Error while finishing device registration: {:?}", err);
+						return Err(DeviceRegisterError::DatabaseError(err));
+					}
 					let response = ApplicationPacket {
 						header: PacketHeader {
 							session_id: [0; 16],
@@ -245,10 +964,10 @@ async fn handle_registration(socket: &mut TcpStream, database: &MainDatabase, re
 							auth_key,
 							camera_id,
 							mac_address,
-							user_id,
+							pairing_token,
 						}),
 					};
-					socket.write_all(&response.to_bytes().unwrap()).await.unwrap();
+					stream.send(response).await.map_err(DeviceRegisterError::ConnectionError)?;
 					return Ok(());
 				} else {
 					log::warn!("Already registered device {:?} is attempting to register", device.device_id);
@@ -267,21 +986,645 @@ async fn handle_registration(socket: &mut TcpStream, database: &MainDatabase, re
 	}
 }
 
-pub struct DeviceBridgeFairing {
-	port: u16
+enum DeviceConnectError {
+	UnknownCameraID,
+	RegistrationIncomplete,
+	InvalidAuthKey,
+	NotApproved,
+	/// Device was rejected by the owner and got told to unregister
+	Rejected,
+	DatabaseError(Error),
+	ConnectionError(std::io::Error),
+}
+
+/// Verifies the device credentials and creates a new session for it. Returns the ID of the created session
+async fn handle_initiate_connection<S: AsyncRead + AsyncWrite + Unpin, D: DeviceStore>(stream: &mut PacketStream<S>, connection: &Connection, sessions: &SessionList, database: &D, connect_packet: InitiateConnectionPacket) -> Result<[u8; 16], DeviceConnectError> {
+	let InitiateConnectionPacket { auth_key, camera_id } = connect_packet;
+	let address = connection.address;
+
+	let device_query = device_dsl::device.find(camera_id);
+	let dev = database.run(move |conn| device_query.first::<Device>(conn)).await;
+	let device = match dev {
+		Ok(device) => device,
+		Err(Error::NotFound) => {
+			log::warn!("Device with unknown ID {:?} is trying to connect", camera_id);
+			return Err(DeviceConnectError::UnknownCameraID);
+		}
+		Err(err) => {
+			log::warn!("Error while retrieving device in connection handler: {:?}", err);
+			return Err(DeviceConnectError::DatabaseError(err));
+		}
+	};
+	if device.registration_first_stage {
+		log::warn!("Device {:?} is trying to connect before finishing registration", camera_id);
+		return Err(DeviceConnectError::RegistrationIncomplete);
+	}
+	if device.auth_key != auth_key {
+		log::warn!("Device {:?} sent an invalid auth key", camera_id);
+		return Err(DeviceConnectError::InvalidAuthKey);
+	}
+	match device.approval {
+		DeviceApproval::Approved => {}
+		DeviceApproval::Pending => {
+			log::info!("Device {:?} is trying to connect before being approved by its owner", camera_id);
+			return Err(DeviceConnectError::NotApproved);
+		}
+		DeviceApproval::Rejected => {
+			log::info!("Telling device {:?} at {} that its owner rejected it", camera_id, address);
+			let response = ApplicationPacket {
+				header: PacketHeader {
+					session_id: [0; 16],
+					buffer_size: 1,
+					is_response: true,
+				},
+				message: packets::Message::UnregisterDevice(UnregisterDevicePacket { success: 0 }),
+			};
+			stream.send(response).await.map_err(DeviceConnectError::ConnectionError)?;
+			// Only removed once the device knows, so it is told again if sending failed
+			storage::delete_device(database, &connection.storage_root, camera_id).await.map_err(DeviceConnectError::DatabaseError)?;
+			return Err(DeviceConnectError::Rejected);
+		}
+	}
+
+	let mut new_session_id = [0u8; 16];
+	{
+		let mut sessions = sessions.lock().unwrap();
+		// A device can only have one session, a new connection replaces the old one
+		sessions.retain(|_, session| {
+			if session.device_id == camera_id {
+				session.closer.cancel();
+				return false;
+			}
+			return true;
+		});
+		rand::thread_rng().fill(&mut new_session_id);
+		while new_session_id == [0; 16] || sessions.contains_key(&new_session_id) {
+			rand::thread_rng().fill(&mut new_session_id);
+		}
+		sessions.insert(new_session_id, Session {
+			device_id: camera_id,
+			owner_id: device.user_id.try_into().unwrap(),
+			address,
+			connected_at: Utc::now(),
+			last_seen: Utc::now(),
+			outgoing: connection.outgoing.clone(),
+			closer: connection.closer.clone(),
+		});
+	}
+	log::info!("Device {:?} connected from {}", camera_id, address);
+
+	let response = ApplicationPacket {
+		header: PacketHeader {
+			session_id: new_session_id,
+			buffer_size: 32,
+			is_response: true,
+		},
+		message: packets::Message::InitiateConnection(InitiateConnectionPacket {
+			camera_id,
+			auth_key,
+		}),
+	};
+	if let Err(err) = stream.send(response).await {
+		sessions.lock().unwrap().remove(&new_session_id);
+		return Err(DeviceConnectError::ConnectionError(err));
+	}
+	return Ok(new_session_id);
+}
+
+/// Removes the device that asked for it and ends its session and connection
+async fn handle_unregistration<S: AsyncRead + AsyncWrite + Unpin, D: DeviceStore>(stream: &mut PacketStream<S>, connection: &mut Connection, sessions: &SessionList, database: &D) -> Result<(), Error> {
+	let session_id = connection.session_id.take().unwrap();
+	let session = sessions.lock().unwrap().remove(&session_id);
+	connection.finished = true;
+	let success = match session {
+		Some(session) => {
+			match storage::delete_device(database, &connection.storage_root, session.device_id).await {
+				Ok(_) | Err(Error::NotFound) => {
+					log::info!("Device {:?} unregistered itself", session.device_id);
+					1
+				}
+				Err(err) => {
+					return Err(err);
+				}
+			}
+		}
+		None => {
+			// The owner removed the device in the meantime
+			log::info!("Device at {} asked for removal after its session ended", connection.address);
+			1
+		}
+	};
+
+	let response = ApplicationPacket {
+		header: PacketHeader {
+			session_id,
+			buffer_size: 1,
+			is_response: true,
+		},
+		message: packets::Message::UnregisterDevice(UnregisterDevicePacket { success }),
+	};
+	if let Err(err) = stream.send(response).await {
+		log::warn!("Couldn't confirm unregistration to {}: {:?}", connection.address, err);
+	}
+	return Ok(());
 }
 
+pub struct DeviceBridgeFairing {}
+
 #[rocket::async_trait]
 impl Fairing for DeviceBridgeFairing {
 	fn info(&self) -> rocket::fairing::Info {
 		return Info {
-			name: "Device bridge initialization",
-			kind: Kind::Ignite
+			name: "Device bridge",
+			kind: Kind::Ignite | Kind::Shutdown
 		};
 	}
 
 	async fn on_ignite(&self, rocket: Rocket<Build>) -> Result<Rocket<Build>, Rocket<Build>> {
-		let db = MainDatabase::get_one(&rocket).await.unwrap();
-		return Ok(rocket.manage(DeviceBridge::new(self.port, db)));
+		let db = match MainDatabase::get_one(&rocket).await {
+			Some(db) => db,
+			None => {
+				log::error!("Device bridge requires the main database to be attached first");
+				return Err(rocket);
+			}
+		};
+		let config = match rocket.figment().extract_inner::<DeviceBridgeConfig>("device_bridge") {
+			Ok(config) => config,
+			Err(err) if err.missing() => DeviceBridgeConfig::default(),
+			Err(err) => {
+				log::error!("Invalid device bridge configuration: {}", err);
+				return Err(rocket);
+			}
+		};
+		match DeviceBridge::new(config, db) {
+			Ok(bridge) => {
+				log::info!("Device bridge listening on TCP {} and UDP {}", bridge.tcp_address(), bridge.udp_address());
+				return Ok(rocket.manage(bridge));
+			}
+			Err(err) => {
+				log::error!("Couldn't start device bridge: {}", err);
+				return Err(rocket);
+			}
+		}
+	}
+
+	async fn on_shutdown(&self, rocket: &Rocket<Orbit>) {
+		if let Some(bridge) = rocket.state::<DeviceBridge>() {
+			bridge.shutdown().await;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use rocket::futures::{SinkExt, StreamExt};
+
+	use chrono::TimeDelta;
+	use rocket::{error::ErrorKind, local::asynchronous::Client, tokio::net::TcpStream};
+
+	use crate::schema::recording::dsl as recording_dsl;
+	use crate::model::{NewRecording, User};
+	use crate::tests_common::{create_local_async_client, create_test_rocket, setup_device, setup_user, TestBridge};
+	use super::*;
+	use super::packets::{EmptyPacket, Message};
+
+	fn request(message: Message) -> ApplicationPacket {
+		return ApplicationPacket {
+			header: PacketHeader {
+				session_id: [0; 16],
+				buffer_size: 0,
+				is_response: false,
+			},
+			message,
+		};
+	}
+
+	#[rocket::async_test]
+	async fn two_stage_registration() {
+		let bridge = TestBridge::new();
+		let user = bridge.setup_user().await;
+		let pairing_token = bridge.setup_pairing_token(&user, TimeDelta::minutes(10)).await;
+		let mut device = bridge.connect();
+
+		device.send(request(Message::RegisterDevice(RegisterDevicePacket {
+			pairing_token,
+			camera_id: [0; 16],
+			auth_key: [5; 16],
+			mac_address: [6; 6],
+		}))).await.unwrap();
+		let response = device.next().await.unwrap().unwrap();
+		assert!(response.header.is_response);
+		let Message::RegisterDevice(first_stage) = response.message else { panic!("Unexpected response {:?}", response) };
+		assert_ne!(first_stage.camera_id, [0; 16]);
+		let registered = bridge.get_device(first_stage.camera_id).await.unwrap();
+		assert!(registered.registration_first_stage);
+		assert_eq!(registered.user_id, user.user_id);
+		assert_eq!(registered.mac_address, vec![6; 6]);
+
+		device.send(request(Message::RegisterDevice(RegisterDevicePacket {
+			auth_key: [9; 16],
+			..first_stage
+		}))).await.unwrap();
+		let response = device.next().await.unwrap().unwrap();
+		assert!(matches!(response.message, Message::RegisterDevice(_)));
+		let registered = bridge.get_device(first_stage.camera_id).await.unwrap();
+		assert!(!registered.registration_first_stage);
+		assert_eq!(registered.auth_key, vec![9; 16]);
+		assert_eq!(registered.approval, DeviceApproval::Pending);
+
+		// Connecting is only possible after the owner approves the device
+		device.send(request(Message::InitiateConnection(InitiateConnectionPacket {
+			camera_id: first_stage.camera_id,
+			auth_key: [9; 16],
+		}))).await.unwrap();
+		assert!(device.next().await.is_none());
+		bridge.set_approval(first_stage.camera_id, DeviceApproval::Approved).await;
+		let mut device = bridge.connect();
+
+		device.send(request(Message::InitiateConnection(InitiateConnectionPacket {
+			camera_id: first_stage.camera_id,
+			auth_key: [9; 16],
+		}))).await.unwrap();
+		let response = device.next().await.unwrap().unwrap();
+		assert!(matches!(response.message, Message::InitiateConnection(_)));
+		let session = bridge.sessions.lock().unwrap().get(&response.header.session_id).cloned().unwrap();
+		assert_eq!(session.device_id, first_stage.camera_id);
+		assert_eq!(session.owner_id.as_slice(), user.user_id.as_slice());
+	}
+
+	#[rocket::async_test]
+	async fn second_stage_requires_pairing_token() {
+		let bridge = TestBridge::new();
+		let user = bridge.setup_user().await;
+		let pairing_token = bridge.setup_pairing_token(&user, TimeDelta::minutes(10)).await;
+		let mut device = bridge.connect();
+		device.send(request(Message::RegisterDevice(RegisterDevicePacket {
+			pairing_token,
+			camera_id: [0; 16],
+			auth_key: [5; 16],
+			mac_address: [6; 6],
+		}))).await.unwrap();
+		let Message::RegisterDevice(first_stage) = device.next().await.unwrap().unwrap().message else { panic!("Expected registration response") };
+
+		// Someone else that learned the device ID
+		let mut attacker = bridge.connect();
+		attacker.send(request(Message::RegisterDevice(RegisterDevicePacket {
+			pairing_token: [7; 16],
+			auth_key: [8; 16],
+			..first_stage
+		}))).await.unwrap();
+		assert!(attacker.next().await.is_none());
+		let registered = bridge.get_device(first_stage.camera_id).await.unwrap();
+		assert!(registered.registration_first_stage);
+		assert_eq!(registered.auth_key, vec![5; 16]);
+
+		device.send(request(Message::RegisterDevice(first_stage))).await.unwrap();
+		assert!(matches!(device.next().await.unwrap().unwrap().message, Message::RegisterDevice(_)));
+		let registered = bridge.get_device(first_stage.camera_id).await.unwrap();
+		assert!(!registered.registration_first_stage);
+		assert_eq!(registered.registration_token, None);
+	}
+
+	#[rocket::async_test]
+	async fn registration_with_unknown_pairing_token() {
+		let bridge = TestBridge::new();
+		let mut device = bridge.connect();
+		device.send(request(Message::RegisterDevice(RegisterDevicePacket {
+			pairing_token: [1; 16],
+			camera_id: [0; 16],
+			auth_key: [5; 16],
+			mac_address: [6; 6],
+		}))).await.unwrap();
+		assert!(device.next().await.is_none());
+	}
+
+	#[rocket::async_test]
+	async fn pairing_token_is_single_use_and_expires() {
+		let bridge = TestBridge::new();
+		let user = bridge.setup_user().await;
+		let expired_token = bridge.setup_pairing_token(&user, TimeDelta::minutes(-1)).await;
+		let mut device = bridge.connect();
+		device.send(request(Message::RegisterDevice(RegisterDevicePacket {
+			pairing_token: expired_token,
+			camera_id: [0; 16],
+			auth_key: [5; 16],
+			mac_address: [6; 6],
+		}))).await.unwrap();
+		assert!(device.next().await.is_none());
+
+		let pairing_token = bridge.setup_pairing_token(&user, TimeDelta::minutes(10)).await;
+		let packet = request(Message::RegisterDevice(RegisterDevicePacket {
+			pairing_token,
+			camera_id: [0; 16],
+			auth_key: [5; 16],
+			mac_address: [6; 6],
+		}));
+		let mut device = bridge.connect();
+		device.send(packet).await.unwrap();
+		assert!(device.next().await.unwrap().is_ok());
+		let mut other_device = bridge.connect();
+		other_device.send(packet).await.unwrap();
+		assert!(other_device.next().await.is_none());
+	}
+
+	#[rocket::async_test]
+	async fn initiate_connection_handshake() {
+		let bridge = TestBridge::new();
+		let user = bridge.setup_user().await;
+		let registered = bridge.setup_device(&user).await;
+		let mut device = bridge.connect();
+		device.send(request(Message::InitiateConnection(InitiateConnectionPacket {
+			camera_id: registered.device_id.clone().try_into().unwrap(),
+			auth_key: [3; 16],
+		}))).await.unwrap();
+		let response = device.next().await.unwrap().unwrap();
+		assert!(response.header.is_response);
+		assert!(matches!(response.message, Message::InitiateConnection(_)));
+		assert_ne!(response.header.session_id, [0; 16]);
+
+		let session = bridge.sessions.lock().unwrap().get(&response.header.session_id).cloned().unwrap();
+		assert_eq!(session.device_id.as_slice(), registered.device_id.as_slice());
+		assert_eq!(session.owner_id.as_slice(), user.user_id.as_slice());
+	}
+
+	#[rocket::async_test]
+	async fn device_unregisters_itself() {
+		let bridge = TestBridge::new();
+		let user = bridge.setup_user().await;
+		let registered = bridge.setup_device(&user).await;
+		let device_id: [u8; 16] = registered.device_id.clone().try_into().unwrap();
+		let frames_directory = bridge.storage_root.join(storage::device_directory(device_id));
+		std::fs::create_dir_all(&frames_directory).unwrap();
+		std::fs::write(frames_directory.join("frame.jpg"), [0xff, 0xd8]).unwrap();
+
+		let mut device = bridge.connect();
+		device.send(request(Message::InitiateConnection(InitiateConnectionPacket {
+			camera_id: device_id,
+			auth_key: [3; 16],
+		}))).await.unwrap();
+		let session_id = device.next().await.unwrap().unwrap().header.session_id;
+
+		let mut unregister = request(Message::UnregisterDevice(UnregisterDevicePacket { success: 0 }));
+		unregister.header.session_id = session_id;
+		device.send(unregister).await.unwrap();
+		let response = device.next().await.unwrap().unwrap();
+		assert!(response.header.is_response);
+		assert!(matches!(response.message, Message::UnregisterDevice(UnregisterDevicePacket { success: 1 })));
+		assert!(device.next().await.is_none());
+		assert!(bridge.get_device(device_id).await.is_none());
+		assert!(bridge.sessions.lock().unwrap().is_empty());
+		assert!(!frames_directory.exists());
+		std::fs::remove_dir_all(&bridge.storage_root).unwrap();
+	}
+
+	#[rocket::async_test]
+	async fn connection_with_invalid_auth_key() {
+		let bridge = TestBridge::new();
+		let mut device = bridge.connect();
+		device.send(request(Message::InitiateConnection(InitiateConnectionPacket {
+			camera_id: [1; 16],
+			auth_key: [2; 16],
+		}))).await.unwrap();
+		assert!(device.next().await.is_none());
+		assert!(bridge.sessions.lock().unwrap().is_empty());
+	}
+
+	#[rocket::async_test]
+	async fn heartbeat_response() {
+		let bridge = TestBridge::new();
+		let mut device = bridge.connect();
+		device.send(request(Message::NoOperation(EmptyPacket {}))).await.unwrap();
+		let response = device.next().await.unwrap().unwrap();
+		assert!(response.header.is_response);
+		assert!(matches!(response.message, Message::NoOperation(_)));
+	}
+
+	#[rocket::async_test]
+	async fn connection_without_session_is_closed() {
+		let bridge = TestBridge::new();
+		let mut device = bridge.connect();
+		let closed = timeout(TestBridge::HANDSHAKE_TIMEOUT * 4, device.next()).await.unwrap();
+		assert!(closed.is_none());
+	}
+
+	#[rocket::async_test]
+	async fn session_outlives_handshake_timeout() {
+		let bridge = TestBridge::new();
+		let user = bridge.setup_user().await;
+		let registered = bridge.setup_device(&user).await;
+		let mut device = bridge.connect();
+		device.send(request(Message::InitiateConnection(InitiateConnectionPacket {
+			camera_id: registered.device_id.clone().try_into().unwrap(),
+			auth_key: [3; 16],
+		}))).await.unwrap();
+		let session_id = device.next().await.unwrap().unwrap().header.session_id;
+
+		sleep(TestBridge::HANDSHAKE_TIMEOUT * 2).await;
+		let mut heartbeat = request(Message::NoOperation(EmptyPacket {}));
+		heartbeat.header.session_id = session_id;
+		device.send(heartbeat).await.unwrap();
+		let response = device.next().await.unwrap().unwrap();
+		assert!(matches!(response.message, Message::NoOperation(_)));
+	}
+
+	#[rocket::async_test]
+	async fn binds_free_ports() {
+		let client = create_local_async_client().await;
+		let bridge = client.rocket().state::<DeviceBridge>().unwrap();
+		assert_ne!(bridge.tcp_address().port(), 0);
+		assert_ne!(bridge.udp_address().port(), 0);
+		TcpStream::connect((Ipv4Addr::LOCALHOST, bridge.tcp_address().port())).await.unwrap();
+	}
+
+	#[rocket::async_test]
+	async fn bind_failure_stops_startup() {
+		let taken = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+		let rocket = create_test_rocket();
+		let figment = rocket.figment().clone()
+			.merge(("device_bridge.tcp_address", "127.0.0.1"))
+			.merge(("device_bridge.tcp_port", taken.local_addr().unwrap().port()));
+		let err = rocket.configure(figment).ignite().await.unwrap_err();
+		assert!(matches!(err.kind(), ErrorKind::FailedFairings(_)));
 	}
-}
\ No newline at end of file
+
+	#[rocket::async_test]
+	async fn device_disconnect() {
+		let bridge = TestBridge::new();
+		let mut device = bridge.connect();
+		device.send(request(Message::Disconnect(EmptyPacket {}))).await.unwrap();
+		assert!(device.next().await.is_none());
+	}
+
+	#[rocket::async_test]
+	async fn shutdown_notifies_devices() {
+		let client = create_local_async_client().await;
+		let user = setup_user(&client).await;
+		let registered = setup_device(&client, &user).await;
+		let bridge = client.rocket().state::<DeviceBridge>().unwrap();
+		let socket = TcpStream::connect((Ipv4Addr::LOCALHOST, bridge.tcp_address().port())).await.unwrap();
+		let mut device = ApplicationPacketCodec::new(256).framed(socket);
+		device.send(request(Message::InitiateConnection(InitiateConnectionPacket {
+			camera_id: registered.device_id.clone().try_into().unwrap(),
+			auth_key: registered.auth_key.clone().try_into().unwrap(),
+		}))).await.unwrap();
+		let session_id = device.next().await.unwrap().unwrap().header.session_id;
+
+		bridge.shutdown().await;
+		// Handlers finished before shutdown returned
+		assert_eq!(bridge.stats().connected_devices, 0);
+		let database = MainDatabase::get_one(client.rocket()).await.unwrap();
+		let query = device_dsl::device.find(registered.device_id.clone());
+		assert!(database.run(move |conn| query.first::<Device>(conn)).await.unwrap().last_seen.is_some());
+		let goodbye = device.next().await.unwrap().unwrap();
+		assert_eq!(goodbye.header.session_id, session_id);
+		assert!(matches!(goodbye.message, Message::Disconnect(_)));
+		assert!(device.next().await.is_none());
+		// Nothing is listening anymore
+		assert!(TcpStream::connect((Ipv4Addr::LOCALHOST, bridge.tcp_address().port())).await.is_err());
+	}
+
+	#[rocket::async_test]
+	async fn shutdown_stores_received_frames() {
+		let storage_root = std::env::temp_dir().join(format!("camera-server-shutdown-{}", std::process::id()));
+		let rocket = create_test_rocket();
+		let figment = rocket.figment().clone().merge(("device_bridge.storage_root", &storage_root));
+		let client = Client::tracked(rocket.configure(figment)).await.unwrap();
+		let user = setup_user(&client).await;
+		let registered = setup_device(&client, &user).await;
+		let bridge = client.rocket().state::<DeviceBridge>().unwrap();
+		for sequence in 0..3 {
+			bridge.frames.send(Arc::new(Frame {
+				device_id: registered.device_id.clone().try_into().unwrap(),
+				sequence,
+				received_at: Utc::now(),
+				data: vec![0xff, 0xd8, sequence as u8],
+			})).unwrap();
+		}
+
+		bridge.shutdown().await;
+		let database = MainDatabase::get_one(client.rocket()).await.unwrap();
+		let stored = database.run(|conn| recording_dsl::recording.count().get_result::<i64>(conn)).await.unwrap();
+		assert_eq!(stored, 3);
+		std::fs::remove_dir_all(storage_root).unwrap();
+	}
+
+	#[rocket::async_test]
+	async fn rejected_device_is_told_to_unregister() {
+		let bridge = TestBridge::new();
+		let user = bridge.setup_user().await;
+		let pairing_token = bridge.setup_pairing_token(&user, TimeDelta::minutes(10)).await;
+		let mut device = bridge.connect();
+		device.send(request(Message::RegisterDevice(RegisterDevicePacket {
+			pairing_token,
+			camera_id: [0; 16],
+			auth_key: [5; 16],
+			mac_address: [6; 6],
+		}))).await.unwrap();
+		let Message::RegisterDevice(first_stage) = device.next().await.unwrap().unwrap().message else { panic!("Expected registration response") };
+		device.send(request(Message::RegisterDevice(first_stage))).await.unwrap();
+		device.next().await.unwrap().unwrap();
+		bridge.set_approval(first_stage.camera_id, DeviceApproval::Rejected).await;
+
+		device.send(request(Message::InitiateConnection(InitiateConnectionPacket {
+			camera_id: first_stage.camera_id,
+			auth_key: [5; 16],
+		}))).await.unwrap();
+		let response = device.next().await.unwrap().unwrap();
+		assert!(response.header.is_response);
+		assert!(matches!(response.message, Message::UnregisterDevice(UnregisterDevicePacket { success: 0 })));
+		assert!(device.next().await.is_none());
+		assert!(bridge.get_device(first_stage.camera_id).await.is_none());
+	}
+
+	#[rocket::async_test]
+	async fn abandoned_registrations_are_removed() {
+		let bridge = TestBridge::new();
+		let user = bridge.setup_user().await;
+		let pairing_token = bridge.setup_pairing_token(&user, TimeDelta::minutes(10)).await;
+		let mut device = bridge.connect();
+		device.send(request(Message::RegisterDevice(RegisterDevicePacket {
+			pairing_token,
+			camera_id: [0; 16],
+			auth_key: [5; 16],
+			mac_address: [6; 6],
+		}))).await.unwrap();
+		let Message::RegisterDevice(first_stage) = device.next().await.unwrap().unwrap().message else { panic!("Expected registration response") };
+
+		sweep_registrations(bridge.store.as_ref(), &bridge.storage_root, Duration::from_secs(60)).await;
+		assert!(bridge.get_device(first_stage.camera_id).await.is_some());
+		sweep_registrations(bridge.store.as_ref(), &bridge.storage_root, Duration::ZERO).await;
+		assert!(bridge.get_device(first_stage.camera_id).await.is_none());
+	}
+
+	#[rocket::async_test]
+	async fn rejected_devices_are_removed() {
+		let bridge = TestBridge::new();
+		let user = bridge.setup_user().await;
+		let registered = bridge.setup_device(&user).await;
+		let device_id: [u8; 16] = registered.device_id.clone().try_into().unwrap();
+		bridge.set_approval(device_id, DeviceApproval::Rejected).await;
+		let frames_directory = bridge.storage_root.join(storage::device_directory(device_id));
+		std::fs::create_dir_all(&frames_directory).unwrap();
+
+		sweep_registrations(bridge.store.as_ref(), &bridge.storage_root, Duration::from_secs(60)).await;
+		assert!(bridge.get_device(device_id).await.is_some());
+		sweep_registrations(bridge.store.as_ref(), &bridge.storage_root, Duration::ZERO).await;
+		assert!(bridge.get_device(device_id).await.is_none());
+		assert!(!frames_directory.exists());
+		std::fs::remove_dir_all(&bridge.storage_root).unwrap();
+	}
+
+	#[rocket::async_test]
+	async fn reset_devices_with_history_are_kept() {
+		let bridge = TestBridge::new();
+		let user = bridge.setup_user().await;
+		let registered = bridge.setup_device(&user).await;
+		let recording = NewRecording {
+			device_id: registered.device_id.clone(),
+			captured_at: Utc::now().naive_utc(),
+			size: 2,
+			path: String::from("frame.jpg"),
+		};
+		bridge.store.run(move |conn| diesel::insert_into(recording_dsl::recording).values(recording).execute(conn)).await.unwrap();
+		let device_id = register_first_stage(&bridge, &user, registered.mac_address.clone().try_into().unwrap()).await;
+		assert_eq!(device_id.as_slice(), registered.device_id.as_slice());
+
+		sweep_registrations(bridge.store.as_ref(), &bridge.storage_root, Duration::ZERO).await;
+		assert!(bridge.get_device(device_id).await.unwrap().registration_first_stage);
+	}
+
+	/// Goes through the first registration stage and returns the assigned device ID
+	async fn register_first_stage(bridge: &TestBridge, user: &User, mac_address: [u8; 6]) -> [u8; 16] {
+		let pairing_token = bridge.setup_pairing_token(user, TimeDelta::minutes(10)).await;
+		let mut device = bridge.connect();
+		device.send(request(Message::RegisterDevice(RegisterDevicePacket {
+			pairing_token,
+			camera_id: [0; 16],
+			auth_key: [5; 16],
+			mac_address,
+		}))).await.unwrap();
+		let Message::RegisterDevice(first_stage) = device.next().await.unwrap().unwrap().message else { panic!("Expected registration response") };
+		return first_stage.camera_id;
+	}
+
+	#[rocket::async_test]
+	async fn reregistration_by_mac_address() {
+		let bridge = TestBridge::new();
+		let user = bridge.setup_user().await;
+		let other_user = bridge.setup_other_user().await;
+
+		let device_id = register_first_stage(&bridge, &user, [6; 6]).await;
+		bridge.set_approval(device_id, DeviceApproval::Approved).await;
+		assert_eq!(register_first_stage(&bridge, &user, [6; 6]).await, device_id);
+		let reused = bridge.get_device(device_id).await.unwrap();
+		assert_eq!(reused.approval, DeviceApproval::Pending);
+		assert!(!reused.mac_conflict);
+
+		let other_device_id = register_first_stage(&bridge, &other_user, [6; 6]).await;
+		assert_ne!(other_device_id, device_id);
+		let conflicting = bridge.get_device(other_device_id).await.unwrap();
+		assert_eq!(conflicting.user_id, other_user.user_id);
+		assert!(conflicting.mac_conflict);
+		assert!(!bridge.get_device(device_id).await.unwrap().mac_conflict);
+	}
+}
@@ -1,7 +1,6 @@
-use std::borrow::Cow;
+use std::fmt::Display;
 
 use deku::prelude::*;
-use rocket::tokio::{io::AsyncReadExt, net::TcpStream};
 
 #[derive(Debug, Clone, Copy, DekuRead, DekuWrite, PartialEq, Eq)]
 pub struct ApplicationPacket {
@@ -30,6 +29,10 @@ pub enum Message {
 	UnregisterDevice(UnregisterDevicePacket),
 	#[deku(id = "0x03")]
 	InitiateConnection(InitiateConnectionPacket),
+	/// Sent by the server when it shuts down, or by a device that is about to close the connection.
+	/// The device should reconnect later with `InitiateConnection`
+	#[deku(id = "0x04")]
+	Disconnect(EmptyPacket),
 }
 
 #[derive(Debug, Clone, Copy, DekuRead, DekuWrite, PartialEq, Eq)]
@@ -37,7 +40,9 @@ pub struct EmptyPacket {}
 
 #[derive(Debug, Clone, Copy, DekuRead, DekuWrite, PartialEq, Eq)]
 pub struct RegisterDevicePacket {
-	pub user_id: [u8; 16],
+	/// Token from `POST /device/pairing-token`, binding the device to the user that created it,
+	/// or to the organization the token was created for. Only checked in the first stage, and echoed back in responses
+	pub pairing_token: [u8; 16],
 	pub camera_id: [u8; 16],
 	pub auth_key: [u8; 16],
 	pub mac_address: [u8; 6],
@@ -57,10 +62,10 @@ pub struct UnregisterDevicePacket {
 #[derive(Debug, Clone, Copy, DekuRead, DekuWrite, PartialEq, Eq)]
 #[deku(id_type = "u8")]
 pub enum ImageChunkType {
-	MiddleChunk = 0x00,
-	FirstChunk = 0x01,
-	LastChunk = 0x02,
-	OnlyChunk = 0x03,
+	Middle = 0x00,
+	First = 0x01,
+	Last = 0x02,
+	Only = 0x03,
 }
 
 #[derive(Debug, Clone, DekuRead, DekuWrite, PartialEq, Eq)]
@@ -72,90 +77,69 @@ pub struct ImageChunk {
 	pub image_bytes: Vec<u8>,
 }
 
+/// Size of [`PacketHeader`] in bytes
+pub const HEADER_SIZE: usize = 21;
+
+impl Message {
+	/// Checks whether `id` (without the `is_response` bit) belongs to a known message
+	pub fn is_known_id(id: u8) -> bool {
+		return matches!(id, 0x00..=0x04);
+	}
+}
+
 /// Enum representing possible errors that can happen when reading a packet from a socket
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub enum PacketReadError {
 	/// Can't fully read a packet from the socket, probably because it was closed on the other end
 	CantRead,
+	/// Reading from the socket failed
+	Io(std::io::Error),
+	/// Header declares a body bigger than allowed. Contains the declared size
+	Oversized(u32),
 	/// Failed to parse header. Contains information about why it failed
-	HeaderParseError(Cow<'static, str>),
+	HeaderParseError(String),
 	/// Failed to parse full packet. Contains information about why it failed
-	PacketParseError(Cow<'static, str>),
+	PacketParseError(String),
+	/// Header contains an unknown message ID. The body was skipped
+	UnknownMessageId(u8),
 }
 
-// Incredibly convoluted reading function since deku does not support async readers
-pub async fn read_packet_async(socket: &mut TcpStream) -> Result<ApplicationPacket, PacketReadError> {
-	let mut header_buffer = [0; 21];
-	match socket.read_exact(&mut header_buffer).await {
-		Ok(read_size) => {
-			log::debug!("Read {} bytes from socket", read_size);
-		}
-		Err(err) => {
-			match err.kind() {
-				std::io::ErrorKind::UnexpectedEof => {
-					return Err(PacketReadError::CantRead);
-				}
-				_ => {
-					panic!("Unhandled error while reading header: {:#?}", err);
-				}
-			}
+impl Display for PacketReadError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			PacketReadError::CantRead => write!(f, "stream ended in the middle of a packet"),
+			PacketReadError::Io(err) => write!(f, "IO error: {}", err),
+			PacketReadError::Oversized(size) => write!(f, "packet body of {} bytes is too big", size),
+			PacketReadError::HeaderParseError(err) => write!(f, "invalid header: {}", err),
+			PacketReadError::PacketParseError(err) => write!(f, "invalid packet: {}", err),
+			PacketReadError::UnknownMessageId(id) => write!(f, "unknown message ID {:#04x}", id),
 		}
 	}
-	let header;
-	match PacketHeader::from_bytes((&header_buffer, 0)) {
-		Ok((_, head)) => {
-			header = head;
-		}
-		Err(DekuError::Parse(err_string)) => {
-			return Err(PacketReadError::HeaderParseError(err_string));
-		}
-		Err(other_err) => {
-			panic!("Unhandled error while parsing header: {:#?}", other_err);
-		}
-	}
-	assert!(header.buffer_size < 100, "Packet buffer size ({}) is suspiciously big", header.buffer_size);
-	let mut packet_buffer = Vec::with_capacity(21 + header.buffer_size as usize);
-	unsafe {
-		// SAFETY: All possible bit values are valid for `usize`.
-		// Required to fill it with `TcpStream::read_exact`
-		packet_buffer.set_len(21 + header.buffer_size as usize);
-	}
-	match socket.read_exact(&mut packet_buffer[21..]).await {
-		Ok(read_size) => {
-			log::debug!("Read {} bytes from socket", read_size);
-		}
-		Err(err) => {
-			match err.kind() {
-				std::io::ErrorKind::UnexpectedEof => {
-					return Err(PacketReadError::CantRead);
-				}
-				_ => {
-					panic!("Unhandled error while reading rest of message: {:#?}", err);
-				}
-			}
-		}
+}
+
+impl PacketReadError {
+	/// Whether the stream can't be read further, because the position of the next packet is unknown or the stream is broken
+	pub fn is_fatal(&self) -> bool {
+		return matches!(self, PacketReadError::CantRead | PacketReadError::Io(_) | PacketReadError::Oversized(_) | PacketReadError::HeaderParseError(_));
 	}
-	packet_buffer[0..21].copy_from_slice(&header_buffer);
+}
 
-	let packet;
-	match ApplicationPacket::from_bytes((&packet_buffer, 0)) {
-		Ok((_, pck)) => {
-			packet = pck;
-		}
-		Err(DekuError::Parse(err_string)) => {
-			return Err(PacketReadError::PacketParseError(err_string));
-		}
-		Err(other_err) => {
-			panic!("Unhandled error while parsing header: {:#?}", other_err);
+impl From<std::io::Error> for PacketReadError {
+	fn from(err: std::io::Error) -> Self {
+		match err.kind() {
+			std::io::ErrorKind::UnexpectedEof => {
+				return PacketReadError::CantRead;
+			}
+			_ => {
+				return PacketReadError::Io(err);
+			}
 		}
 	}
-	
-	return Ok(packet);
 }
 
 #[cfg(test)]
 mod test {
-    use std::{assert_matches::assert_matches, mem::size_of};
+    use std::mem::size_of;
 
     use deku::DekuContainerWrite;
 
@@ -172,7 +156,7 @@ mod test {
 		];
 
 		let decoded = ApplicationPacket::try_from(data.as_ref()).unwrap();
-		assert_matches!(decoded.message, Message::NoOperation(_));
+		assert!(matches!(decoded.message, Message::NoOperation(_)));
 		assert!(!decoded.header.is_response);
 	}
 
@@ -192,9 +176,9 @@ mod test {
 		assert!(decoded.header.is_response);
 		assert_eq!(decoded.header.buffer_size, 54);
 		assert_eq!(decoded.header.session_id, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
-		assert_matches!(decoded.message, Message::RegisterDevice(_));
+		assert!(matches!(decoded.message, Message::RegisterDevice(_)));
 		if let Message::RegisterDevice(inner) = decoded.message {
-			assert_eq!(inner.user_id, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+			assert_eq!(inner.pairing_token, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
 			assert_eq!(inner.camera_id, [15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0]);
 			assert_eq!(inner.auth_key, [16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 29, 30, 31, 32]);
 			assert_eq!(inner.mac_address, [0, 1, 2, 3, 4, 5]);
@@ -229,7 +213,7 @@ mod test {
 	fn image_chunk() {
 		let data = ImageChunk {
 			chunk_id: 5, 
-			chunk_type: ImageChunkType::MiddleChunk,
+			chunk_type: ImageChunkType::Middle,
 			session_id: [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
 			image_bytes: vec![0; 16384],
 		};
@@ -239,8 +223,8 @@ mod test {
 
 		let decoded = ImageChunk::try_from(encoded.as_ref()).unwrap();
 		assert_eq!(decoded.chunk_id, 5);
-		assert_eq!(decoded.chunk_type, ImageChunkType::MiddleChunk);
+		assert_eq!(decoded.chunk_type, ImageChunkType::Middle);
 		assert_eq!(decoded.session_id, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
 		assert_eq!(decoded.image_bytes.len(), 16384);
 	}
-}
\ No newline at end of file
+}
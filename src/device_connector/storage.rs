@@ -0,0 +1,94 @@
+use std::{fmt::Display, path::{Path, PathBuf}};
+
+use diesel::{insert_into, QueryResult, RunQueryDsl};
+use rocket::tokio::fs;
+
+use crate::{model::{Device, NewRecording}, MainDatabase};
+use crate::schema::recording::dsl as recording_dsl;
+
+use super::{frames::Frame, store::DeviceStore};
+
+#[derive(Debug)]
+pub enum StorageError {
+	Io(std::io::Error),
+	Database(diesel::result::Error),
+}
+
+impl Display for StorageError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			StorageError::Io(err) => write!(f, "IO error: {}", err),
+			StorageError::Database(err) => write!(f, "database error: {}", err),
+		}
+	}
+}
+
+/// Directory with all frames of the device, relative to the storage root
+pub fn device_directory(device_id: [u8; 16]) -> PathBuf {
+	return PathBuf::from(hex::encode(device_id));
+}
+
+/// Path of the frame file relative to the storage root: `<device_id hex>/<date>/<time>-<sequence>.jpg`.
+/// The sequence keeps frames received within the same millisecond apart
+pub fn frame_path(frame: &Frame) -> PathBuf {
+	let mut path = device_directory(frame.device_id);
+	path.push(frame.received_at.format("%Y-%m-%d").to_string());
+	path.push(format!("{}-{}.jpg", frame.received_at.format("%H-%M-%S%.3f"), frame.sequence));
+	return path;
+}
+
+/// Writes the frame under `storage_root` and records it in the database
+pub async fn store_frame(storage_root: &Path, frame: &Frame, database: &MainDatabase) -> Result<(), StorageError> {
+	let relative_path = frame_path(frame);
+	let full_path = storage_root.join(&relative_path);
+	if let Some(directory) = full_path.parent() {
+		fs::create_dir_all(directory).await.map_err(StorageError::Io)?;
+	}
+	fs::write(&full_path, &frame.data).await.map_err(StorageError::Io)?;
+
+	let query = insert_into(recording_dsl::recording).values(NewRecording {
+		device_id: Vec::from(frame.device_id),
+		captured_at: frame.received_at.naive_utc(),
+		size: frame.data.len() as i32,
+		path: relative_path.to_string_lossy().into_owned(),
+	});
+	database.run(move |conn| query.execute(conn)).await.map_err(StorageError::Database)?;
+	log::debug!("Stored frame {} of device {:?} in {}", frame.sequence, frame.device_id, full_path.display());
+	return Ok(());
+}
+
+/// Deletes the device with its recordings using [`Device::delete`], then removes its frames from `storage_root`.
+/// The files are also removed if the device was already deleted. Failing to remove them is only logged
+pub async fn delete_device<D: DeviceStore>(database: &D, storage_root: &Path, device_id: [u8; 16]) -> QueryResult<()> {
+	let result = database.run(move |conn| Device::delete(conn, Vec::from(device_id))).await;
+	if let Ok(_) | Err(diesel::result::Error::NotFound) = result {
+		match fs::remove_dir_all(storage_root.join(device_directory(device_id))).await {
+			Ok(_) => {
+				log::debug!("Removed stored frames of device {:?}", device_id);
+			}
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+			Err(err) => {
+				log::error!("Couldn't remove stored frames of device {:?}: {}", device_id, err);
+			}
+		}
+	}
+	return result;
+}
+
+#[cfg(test)]
+mod tests {
+	use chrono::{TimeZone, Utc};
+
+	use super::*;
+
+	#[test]
+	fn frame_path_layout() {
+		let frame = Frame {
+			device_id: [0xab; 16],
+			sequence: 42,
+			received_at: Utc.with_ymd_and_hms(2024, 7, 3, 14, 5, 9).unwrap(),
+			data: vec![],
+		};
+		assert_eq!(frame_path(&frame), PathBuf::from("abababababababababababababababab/2024-07-03/14-05-09.000-42.jpg"));
+	}
+}
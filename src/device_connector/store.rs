@@ -0,0 +1,25 @@
+use diesel::SqliteConnection;
+
+use crate::MainDatabase;
+
+/// Database with devices and users used by the connection handlers.
+/// Lets the handlers run against a database outside of Rocket in tests
+#[rocket::async_trait]
+pub trait DeviceStore: Send + Sync + 'static {
+	/// Runs `f` with a connection to the database
+	async fn run<F, R>(&self, f: F) -> R
+	where
+		F: FnOnce(&mut SqliteConnection) -> R + Send + 'static,
+		R: Send + 'static;
+}
+
+#[rocket::async_trait]
+impl DeviceStore for MainDatabase {
+	async fn run<F, R>(&self, f: F) -> R
+	where
+		F: FnOnce(&mut SqliteConnection) -> R + Send + 'static,
+		R: Send + 'static,
+	{
+		return MainDatabase::run(self, f).await;
+	}
+}
@@ -0,0 +1,1090 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use chrono_tz::Tz;
+use diesel::{insert_into, update, BoolExpressionMethods, Connection, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+use rand::Rng;
+use rocket::{delete, fs::NamedFile, futures::{SinkExt, StreamExt}, get, patch, post, put, http::{ContentType, Status}, response::stream::ByteStream, routes, serde::json::{self, Json}, tokio::{select, sync::broadcast::error::RecvError}, Route, Shutdown, State};
+use rocket_ws::{Channel, Message, WebSocket};
+use serde::{Deserialize, Serialize};
+
+use crate::{auth::{AuthError, AuthenticatedUser, StreamToken}, device_connector::{storage, DeviceBridge}, model::{Device, DeviceAccess, DeviceApproval, DeviceRole, DeviceTag, OrganizationMember, PairingToken, Recording, User}, organization_routes::find_membership, routes_common::{error_response, ErrorResponse}, MainDatabase};
+use crate::schema::device::dsl as device_dsl;
+use crate::schema::device_access::dsl as access_dsl;
+use crate::schema::device_tag::dsl as tag_dsl;
+use crate::schema::organization_member::dsl as member_dsl;
+use crate::schema::pairing_token::dsl as pairing_dsl;
+use crate::schema::recording::dsl as recording_dsl;
+
+/// Separates consecutive images of a MJPEG stream
+const MJPEG_BOUNDARY: &str = "frame";
+/// Longest allowed device name, in characters
+const MAX_NAME_LENGTH: usize = 64;
+/// Longest allowed device location, in characters
+const MAX_LOCATION_LENGTH: usize = 128;
+/// Longest allowed tag, in characters
+const MAX_TAG_LENGTH: usize = 32;
+const MAX_TAG_COUNT: usize = 32;
+/// Longer than any IANA timezone name, checked before parsing
+const MAX_TIMEZONE_LENGTH: usize = 64;
+/// Time for which a pairing token can be used to register a device
+const PAIRING_TOKEN_LIFETIME: TimeDelta = TimeDelta::minutes(10);
+/// Time for which a stream token can be used to open a stream. Open streams aren't closed when it expires
+const STREAM_TOKEN_LIFETIME: TimeDelta = TimeDelta::minutes(1);
+/// Recordings listed when `limit` isn't given
+const DEFAULT_RECORDING_PAGE_SIZE: i64 = 100;
+const MAX_RECORDING_PAGE_SIZE: i64 = 1000;
+
+pub fn routes() -> Vec<Route> {
+	return routes![
+		list_devices,
+		list_registrations,
+		create_pairing_token,
+		get_device,
+		rename_device,
+		update_device,
+		approve_device,
+		reject_device,
+		create_stream_token,
+		stream_mjpeg,
+		live_websocket,
+		delete_device,
+		device_status,
+		list_recordings,
+		get_recording
+	];
+}
+
+/// Authenticates with the `Authorization` header, or for clients that can't set headers with a stream token
+/// from [`create_stream_token`] for the device with hex encoded `id`, passed in the `token` query parameter
+async fn resolve_user(database: &MainDatabase, auth: Result<AuthenticatedUser, AuthError>, id: &str, token: Option<&str>) -> Result<User, ErrorResponse> {
+	match (auth, token) {
+		(Ok(auth), _) => {
+			return Ok(auth.user);
+		}
+		(Err(AuthError::MissingToken), Some(token)) => {
+			let device_id = parse_device_id(id)?;
+			return AuthenticatedUser::authenticate_stream(database, token, &device_id).await.map(|auth| auth.user).map_err(AuthError::response);
+		}
+		(Err(err), _) => {
+			return Err(err.response());
+		}
+	}
+}
+
+/// Decodes the hex encoded device ID from the URL
+pub fn parse_device_id(id: &str) -> Result<Vec<u8>, ErrorResponse> {
+	match hex::decode(id) {
+		Ok(device_id) if device_id.len() == 16 => {
+			return Ok(device_id);
+		}
+		_ => {
+			return Err(error_response(Status::NotFound, "DeviceNotFound", "Device with specified ID does not exist"));
+		}
+	}
+}
+
+/// Finds the device with hex encoded `id` that `user` owns, or was given at least the `required` role for
+/// through a share or membership in the organization owning it. With `required` set to `None` only users in full control are let through.
+/// Returns the device with the role of the user, which is `None` for the owner and admins of the organization owning the device
+pub async fn find_device(database: &MainDatabase, user: &User, id: &str, required: Option<DeviceRole>) -> Result<(Device, Option<DeviceRole>), ErrorResponse> {
+	let device_id = parse_device_id(id)?;
+
+	let user_id = user.user_id.clone();
+	let found = database.run(move |conn| {
+		let device = device_dsl::device.find(device_id.clone()).first::<Device>(conn)?;
+		let member_role = match &device.organization_id {
+			Some(organization_id) => member_dsl::organization_member
+				.find((organization_id.clone(), user_id.clone()))
+				.select(member_dsl::role)
+				.first::<DeviceRole>(conn)
+				.optional()?,
+			None if device.user_id == user_id => {
+				return Ok((device, None));
+			}
+			None => None,
+		};
+		if member_role == Some(DeviceRole::Admin) {
+			return Ok((device, None));
+		}
+		let shared_role = access_dsl::device_access.find((device_id, user_id)).select(access_dsl::role).first::<DeviceRole>(conn).optional()?;
+		match member_role.max(shared_role) {
+			Some(role) => Ok((device, Some(role))),
+			None => Err(diesel::result::Error::NotFound),
+		}
+	}).await;
+	match found {
+		Ok((device, None)) => {
+			return Ok((device, None));
+		}
+		Ok((device, Some(role))) if required.is_some_and(|required| role >= required) => {
+			return Ok((device, Some(role)));
+		}
+		Ok(_) => {
+			return Err(error_response(Status::Forbidden, "InsufficientRole", "Your role doesn't allow this action on the device"));
+		}
+		// Devices of other users are reported as nonexistent to not reveal their IDs
+		Err(diesel::result::Error::NotFound) => {
+			return Err(error_response(Status::NotFound, "DeviceNotFound", "Device with specified ID does not exist"));
+		}
+		Err(error) => {
+			log::error!("Error while retrieving device: {:?}", error);
+			return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+		}
+	}
+}
+
+/// Finds the device with hex encoded `id` that belongs to `user` or to an organization where `user` is an admin
+pub async fn find_owned_device(database: &MainDatabase, user: &User, id: &str) -> Result<Device, ErrorResponse> {
+	return find_device(database, user, id, None).await.map(|(device, _)| device);
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StreamTokenResult {
+	/// Passed as the `token` query parameter of the stream routes of the device
+	pub token: String,
+	pub expires_at: DateTime<Utc>,
+}
+
+/// Issues a short-lived token for opening live streams of the device without the `Authorization` header,
+/// so the login token doesn't have to be put into URLs
+#[post("/<id>/stream-token")]
+async fn create_stream_token(database: MainDatabase, auth: AuthenticatedUser, id: &str) -> Result<Json<StreamTokenResult>, ErrorResponse> {
+	let (device, _) = find_device(&database, &auth.user, id, Some(DeviceRole::Viewer)).await?;
+	let expires_at = Utc::now() + STREAM_TOKEN_LIFETIME;
+	let token = StreamToken::new(&auth.user.user_id, &device.device_id, &auth.session_id, expires_at).encode();
+	return Ok(Json::from(StreamTokenResult { token, expires_at }));
+}
+
+/// Streams live frames of the device as `multipart/x-mixed-replace`
+#[get("/<id>/stream.mjpeg?<token>")]
+async fn stream_mjpeg(database: MainDatabase, bridge: &State<DeviceBridge>, mut shutdown: Shutdown, auth: Result<AuthenticatedUser, AuthError>, id: &str, token: Option<&str>) -> Result<(ContentType, ByteStream![Vec<u8>]), ErrorResponse> {
+	let user = resolve_user(&database, auth, id, token).await?;
+	let (device, _) = find_device(&database, &user, id, Some(DeviceRole::Viewer)).await?;
+	let device_id: [u8; 16] = device.device_id.try_into().unwrap();
+
+	let mut frames = bridge.subscribe_frames();
+	let content_type = ContentType::new("multipart", "x-mixed-replace").with_params(("boundary", MJPEG_BOUNDARY));
+	let stream = ByteStream! {
+		loop {
+			let frame = select! {
+				received = frames.recv() => {
+					match received {
+						Ok(frame) => frame,
+						Err(RecvError::Lagged(count)) => {
+							log::debug!("MJPEG stream of device {:?} skipped {} frames", device_id, count);
+							continue;
+						}
+						Err(RecvError::Closed) => break,
+					}
+				}
+				_ = &mut shutdown => break,
+			};
+			if frame.device_id != device_id {
+				continue;
+			}
+			yield format!("--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n", MJPEG_BOUNDARY, frame.data.len()).into_bytes();
+			yield frame.data.clone();
+			yield b"\r\n".to_vec();
+		}
+	};
+	return Ok((content_type, stream));
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeviceStatus {
+	pub online: bool,
+	/// Time of the last heartbeat, `None` if the device never connected
+	pub last_seen: Option<DateTime<Utc>>,
+	/// Start of the current session, `None` if the device is offline
+	pub connected_since: Option<DateTime<Utc>>,
+}
+
+impl DeviceStatus {
+	/// Takes the state of the live session if the device is connected, otherwise the last seen time stored in the database
+	fn of(device: &Device, bridge: &DeviceBridge) -> Self {
+		match bridge.device_session(device.device_id.clone().try_into().unwrap()) {
+			Some(session) => {
+				return Self {
+					online: true,
+					last_seen: Some(session.last_seen),
+					connected_since: Some(session.connected_at),
+				};
+			}
+			None => {
+				return Self {
+					online: false,
+					last_seen: device.last_seen.map(|last_seen| last_seen.and_utc()),
+					connected_since: None,
+				};
+			}
+		}
+	}
+}
+
+/// Reports whether the device is connected and when it was last heard from
+#[get("/<id>/status")]
+async fn device_status(database: MainDatabase, bridge: &State<DeviceBridge>, auth: AuthenticatedUser, id: &str) -> Result<Json<DeviceStatus>, ErrorResponse> {
+	let (device, _) = find_device(&database, &auth.user, id, Some(DeviceRole::Viewer)).await?;
+	return Ok(Json::from(DeviceStatus::of(&device, bridge)));
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RecordingInfo {
+	/// Used in the download URL. Later recordings have bigger IDs
+	pub recording_id: i32,
+	pub captured_at: DateTime<Utc>,
+	/// Size of the image in bytes
+	pub size: i32,
+}
+
+impl From<Recording> for RecordingInfo {
+	fn from(recording: Recording) -> Self {
+		return Self {
+			recording_id: recording.recording_id,
+			captured_at: recording.captured_at.and_utc(),
+			size: recording.size,
+		};
+	}
+}
+
+/// Lists stored frames of the device, newest first. Pages continue with `before` set to the last `recording_id` of the previous page
+#[get("/<id>/recordings?<before>&<limit>")]
+async fn list_recordings(database: MainDatabase, auth: AuthenticatedUser, id: &str, before: Option<i32>, limit: Option<i64>) -> Result<Json<Vec<RecordingInfo>>, ErrorResponse> {
+	let (device, _) = find_device(&database, &auth.user, id, Some(DeviceRole::Operator)).await?;
+	let page_size = limit.unwrap_or(DEFAULT_RECORDING_PAGE_SIZE).clamp(1, MAX_RECORDING_PAGE_SIZE);
+	let found = database.run(move |conn| {
+		let mut query = recording_dsl::recording.filter(recording_dsl::device_id.eq(device.device_id)).into_boxed();
+		if let Some(before) = before {
+			query = query.filter(recording_dsl::recording_id.lt(before));
+		}
+		return query.order(recording_dsl::recording_id.desc()).limit(page_size).load::<Recording>(conn);
+	}).await;
+	match found {
+		Ok(recordings) => {
+			return Ok(Json::from(recordings.into_iter().map(RecordingInfo::from).collect::<Vec<_>>()));
+		}
+		Err(error) => {
+			log::error!("Error while listing recordings: {:?}", error);
+			return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+		}
+	}
+}
+
+/// Downloads a stored frame of the device as JPEG
+#[get("/<id>/recordings/<recording_id>")]
+async fn get_recording(database: MainDatabase, bridge: &State<DeviceBridge>, auth: AuthenticatedUser, id: &str, recording_id: i32) -> Result<NamedFile, ErrorResponse> {
+	let (device, _) = find_device(&database, &auth.user, id, Some(DeviceRole::Operator)).await?;
+	let found = database.run(move |conn| recording_dsl::recording.find(recording_id).filter(recording_dsl::device_id.eq(device.device_id)).first::<Recording>(conn)).await;
+	let recording = match found {
+		Ok(recording) => recording,
+		Err(diesel::result::Error::NotFound) => {
+			return Err(error_response(Status::NotFound, "RecordingNotFound", "Recording with specified ID does not exist"));
+		}
+		Err(error) => {
+			log::error!("Error while retrieving recording: {:?}", error);
+			return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+		}
+	};
+	match NamedFile::open(bridge.storage_root().join(&recording.path)).await {
+		Ok(file) => {
+			return Ok(file);
+		}
+		Err(error) => {
+			log::warn!("Couldn't open recording {} at {}: {}", recording.recording_id, recording.path, error);
+			return Err(error_response(Status::NotFound, "RecordingNotFound", "Recording with specified ID does not exist"));
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum RegistrationStage {
+	/// Device got its ID, but didn't confirm it with the second registration packet yet
+	FirstStage,
+	Completed,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeviceInfo {
+	/// Hex encoded, used in device URLs
+	pub device_id: String,
+	/// Hex encoded
+	pub mac_address: String,
+	pub name: Option<String>,
+	pub location: Option<String>,
+	/// Sorted alphabetically
+	pub tags: Vec<String>,
+	pub timezone: Option<String>,
+	pub registration_stage: RegistrationStage,
+	/// New devices have to be approved before they can connect
+	pub approval: DeviceApproval,
+	/// The MAC address was registered under another account when this device registered
+	pub mac_conflict: bool,
+	/// Hex encoded ID of the organization owning the device
+	pub organization_id: Option<String>,
+	/// Role the device was shared with, `None` if the user owns the device
+	pub role: Option<DeviceRole>,
+	pub status: DeviceStatus,
+}
+
+impl DeviceInfo {
+	pub fn new(device: Device, role: Option<DeviceRole>, tags: Vec<String>, bridge: &DeviceBridge) -> Self {
+		let status = DeviceStatus::of(&device, bridge);
+		return Self {
+			device_id: hex::encode(device.device_id),
+			mac_address: hex::encode(device.mac_address),
+			name: device.name,
+			location: device.location,
+			tags,
+			timezone: device.timezone,
+			registration_stage: if device.registration_first_stage { RegistrationStage::FirstStage } else { RegistrationStage::Completed },
+			approval: device.approval,
+			mac_conflict: device.mac_conflict,
+			organization_id: device.organization_id.map(hex::encode),
+			role,
+			status,
+		};
+	}
+}
+
+/// Tags of the devices, keyed by device ID. Devices without tags are missing from the map
+pub async fn load_tags(database: &MainDatabase, device_ids: Vec<Vec<u8>>) -> Result<HashMap<Vec<u8>, Vec<String>>, ErrorResponse> {
+	let query = tag_dsl::device_tag.filter(tag_dsl::device_id.eq_any(device_ids)).order(tag_dsl::tag);
+	match database.run(move |conn| query.load::<DeviceTag>(conn)).await {
+		Ok(tags) => {
+			let mut result: HashMap<Vec<u8>, Vec<String>> = HashMap::new();
+			for DeviceTag { device_id, tag } in tags {
+				result.entry(device_id).or_default().push(tag);
+			}
+			return Ok(result);
+		}
+		Err(error) => {
+			log::error!("Error while retrieving device tags: {:?}", error);
+			return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+		}
+	}
+}
+
+/// Lists all devices of the user, including ones that didn't finish registration, devices of organizations the user is a member of,
+/// and devices shared with the user. With `tag` given, only devices having all of the tags are listed
+#[get("/?<tag>")]
+async fn list_devices(database: MainDatabase, bridge: &State<DeviceBridge>, auth: AuthenticatedUser, tag: Vec<String>) -> Result<Json<Vec<DeviceInfo>>, ErrorResponse> {
+	let user_id = auth.user.user_id;
+	let listed = database.run(move |conn| {
+		let shared_roles: HashMap<Vec<u8>, DeviceRole> = access_dsl::device_access
+			.filter(access_dsl::user_id.eq(user_id.clone()))
+			.load::<DeviceAccess>(conn)?
+			.into_iter()
+			.map(|access| (access.device_id, access.role))
+			.collect();
+		let member_roles: HashMap<Vec<u8>, DeviceRole> = member_dsl::organization_member
+			.filter(member_dsl::user_id.eq(user_id.clone()))
+			.load::<OrganizationMember>(conn)?
+			.into_iter()
+			.map(|member| (member.organization_id, member.role))
+			.collect();
+		let devices = device_dsl::device
+			.filter(device_dsl::user_id.eq(user_id.clone()).and(device_dsl::organization_id.is_null())
+				.or(device_dsl::organization_id.eq_any(member_roles.keys().cloned().collect::<Vec<_>>()))
+				.or(device_dsl::device_id.eq_any(shared_roles.keys().cloned().collect::<Vec<_>>())))
+			.order(device_dsl::device_id)
+			.load::<Device>(conn)?;
+		let roles: HashMap<Vec<u8>, Option<DeviceRole>> = devices.iter().map(|device| {
+			let member_role = device.organization_id.as_ref().and_then(|organization_id| member_roles.get(organization_id).copied());
+			let role = match member_role {
+				Some(DeviceRole::Admin) => None,
+				None if device.organization_id.is_none() && device.user_id == user_id => None,
+				_ => member_role.max(shared_roles.get(&device.device_id).copied()),
+			};
+			return (device.device_id.clone(), role);
+		}).collect();
+		return Ok::<_, diesel::result::Error>((devices, roles));
+	}).await;
+	let (devices, roles) = match listed {
+		Ok(listed) => listed,
+		Err(error) => {
+			log::error!("Error while listing devices: {:?}", error);
+			return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+		}
+	};
+	let mut tags = load_tags(&database, devices.iter().map(|device| device.device_id.clone()).collect()).await?;
+
+	let mut result = Vec::new();
+	for device in devices {
+		let device_tags = tags.remove(&device.device_id).unwrap_or_default();
+		if tag.iter().all(|wanted| device_tags.contains(wanted)) {
+			let role = roles.get(&device.device_id).copied().flatten();
+			result.push(DeviceInfo::new(device, role, device_tags, bridge));
+		}
+	}
+	return Ok(Json::from(result));
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RegistrationInfo {
+	/// Hex encoded
+	pub device_id: String,
+	/// Hex encoded
+	pub mac_address: String,
+	pub created_at: DateTime<Utc>,
+	/// Time after which the registration is removed if the device doesn't finish it
+	pub expires_at: DateTime<Utc>,
+}
+
+/// Lists devices of the user and of organizations where the user is an admin that are in the first registration stage
+#[get("/registrations")]
+async fn list_registrations(database: MainDatabase, bridge: &State<DeviceBridge>, auth: AuthenticatedUser) -> Result<Json<Vec<RegistrationInfo>>, ErrorResponse> {
+	let user_id = auth.user.user_id;
+	let listed = database.run(move |conn| {
+		let administered = member_dsl::organization_member
+			.filter(member_dsl::user_id.eq(user_id.clone()))
+			.filter(member_dsl::role.eq(DeviceRole::Admin))
+			.select(member_dsl::organization_id)
+			.load::<Vec<u8>>(conn)?;
+		return device_dsl::device
+			.filter(device_dsl::user_id.eq(user_id).and(device_dsl::organization_id.is_null()).or(device_dsl::organization_id.eq_any(administered)))
+			.filter(device_dsl::registration_first_stage.eq(true))
+			.order(device_dsl::created_at)
+			.load::<Device>(conn);
+	}).await;
+	match listed {
+		Ok(devices) => {
+			let timeout = bridge.config().registration_timeout();
+			return Ok(Json::from(devices.into_iter().map(|device| RegistrationInfo {
+				device_id: hex::encode(device.device_id),
+				mac_address: hex::encode(device.mac_address),
+				created_at: device.created_at.and_utc(),
+				expires_at: device.created_at.and_utc() + timeout,
+			}).collect::<Vec<_>>()));
+		}
+		Err(error) => {
+			log::error!("Error while listing registrations: {:?}", error);
+			return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+		}
+	}
+}
+
+#[get("/<id>")]
+async fn get_device(database: MainDatabase, bridge: &State<DeviceBridge>, auth: AuthenticatedUser, id: &str) -> Result<Json<DeviceInfo>, ErrorResponse> {
+	let (device, role) = find_device(&database, &auth.user, id, Some(DeviceRole::Viewer)).await?;
+	let tags = load_tags(&database, vec![device.device_id.clone()]).await?.remove(&device.device_id).unwrap_or_default();
+	return Ok(Json::from(DeviceInfo::new(device, role, tags, bridge)));
+}
+
+/// Trims the value and checks its length. Empty values are turned into `None`
+fn clean_text(value: &str, max_length: usize, code: &str, field: &str) -> Result<Option<String>, ErrorResponse> {
+	let value = value.trim();
+	if value.chars().count() > max_length {
+		return Err(error_response(Status::BadRequest, code, &format!("{} can have at most {} characters", field, max_length)));
+	}
+	if value.is_empty() {
+		return Ok(None);
+	}
+	return Ok(Some(String::from(value)));
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RenameDeviceData {
+	/// Empty name removes the current one
+	pub name: String,
+}
+
+#[put("/<id>/name", data = "<data>")]
+async fn rename_device(database: MainDatabase, bridge: &State<DeviceBridge>, auth: AuthenticatedUser, id: &str, data: Json<RenameDeviceData>) -> Result<Json<DeviceInfo>, ErrorResponse> {
+	let (mut device, role) = find_device(&database, &auth.user, id, Some(DeviceRole::Operator)).await?;
+	device.name = clean_text(&data.name, MAX_NAME_LENGTH, "InvalidName", "Device name")?;
+
+	let query = update(device_dsl::device.find(device.device_id.clone())).set(device_dsl::name.eq(device.name.clone()));
+	if let Err(error) = database.run(move |conn| query.execute(conn)).await {
+		log::error!("Error while renaming device: {:?}", error);
+		return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+	}
+	let tags = load_tags(&database, vec![device.device_id.clone()]).await?.remove(&device.device_id).unwrap_or_default();
+	return Ok(Json::from(DeviceInfo::new(device, role, tags, bridge)));
+}
+
+/// Fields that are missing stay unchanged, empty strings remove the value
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct UpdateDeviceData {
+	pub name: Option<String>,
+	pub location: Option<String>,
+	/// Replaces all tags of the device
+	pub tags: Option<Vec<String>>,
+	/// IANA name, like `Europe/Warsaw`
+	pub timezone: Option<String>,
+}
+
+/// Trims, deduplicates and sorts the tags, rejecting empty or too long ones
+fn clean_tags(tags: Vec<String>) -> Result<Vec<String>, ErrorResponse> {
+	let mut result = Vec::new();
+	for tag in tags {
+		match clean_text(&tag, MAX_TAG_LENGTH, "InvalidTag", "Tag")? {
+			Some(tag) => result.push(tag),
+			None => {
+				return Err(error_response(Status::BadRequest, "InvalidTag", "Tags can't be empty"));
+			}
+		}
+	}
+	result.sort();
+	result.dedup();
+	if result.len() > MAX_TAG_COUNT {
+		return Err(error_response(Status::BadRequest, "InvalidTag", &format!("Device can have at most {} tags", MAX_TAG_COUNT)));
+	}
+	return Ok(result);
+}
+
+/// Changes the name, location, tags or timezone of the device
+#[patch("/<id>", data = "<data>")]
+async fn update_device(database: MainDatabase, bridge: &State<DeviceBridge>, auth: AuthenticatedUser, id: &str, data: Json<UpdateDeviceData>) -> Result<Json<DeviceInfo>, ErrorResponse> {
+	let (mut device, role) = find_device(&database, &auth.user, id, Some(DeviceRole::Operator)).await?;
+	let data = data.0;
+	if let Some(name) = data.name {
+		device.name = clean_text(&name, MAX_NAME_LENGTH, "InvalidName", "Device name")?;
+	}
+	if let Some(location) = data.location {
+		device.location = clean_text(&location, MAX_LOCATION_LENGTH, "InvalidLocation", "Location")?;
+	}
+	if let Some(timezone) = data.timezone {
+		device.timezone = clean_text(&timezone, MAX_TIMEZONE_LENGTH, "InvalidTimezone", "Timezone")?;
+		if let Some(timezone) = &device.timezone {
+			if timezone.parse::<Tz>().is_err() {
+				return Err(error_response(Status::BadRequest, "InvalidTimezone", "Timezone must be an IANA timezone name, like Europe/Warsaw"));
+			}
+		}
+	}
+	let tags = data.tags.map(clean_tags).transpose()?;
+
+	let device_id = device.device_id.clone();
+	let changes = (device_dsl::name.eq(device.name.clone()), device_dsl::location.eq(device.location.clone()), device_dsl::timezone.eq(device.timezone.clone()));
+	let new_tags = tags.clone();
+	let updated = database.run(move |conn| conn.transaction(|conn| {
+		update(device_dsl::device.find(device_id.clone())).set(changes).execute(conn)?;
+		if let Some(tags) = new_tags {
+			diesel::delete(tag_dsl::device_tag.filter(tag_dsl::device_id.eq(device_id.clone()))).execute(conn)?;
+			let rows = tags.into_iter().map(|tag| DeviceTag { device_id: device_id.clone(), tag }).collect::<Vec<_>>();
+			insert_into(tag_dsl::device_tag).values(rows).execute(conn)?;
+		}
+		return diesel::result::QueryResult::Ok(());
+	})).await;
+	if let Err(error) = updated {
+		log::error!("Error while updating device: {:?}", error);
+		return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+	}
+
+	let tags = match tags {
+		Some(tags) => tags,
+		None => load_tags(&database, vec![device.device_id.clone()]).await?.remove(&device.device_id).unwrap_or_default(),
+	};
+	return Ok(Json::from(DeviceInfo::new(device, role, tags, bridge)));
+}
+
+/// Approves or rejects a device waiting for approval
+async fn decide_approval(database: &MainDatabase, bridge: &DeviceBridge, user: &User, id: &str, approval: DeviceApproval) -> Result<Json<DeviceInfo>, ErrorResponse> {
+	let (mut device, role) = find_device(database, user, id, Some(DeviceRole::Admin)).await?;
+	if device.approval != DeviceApproval::Pending {
+		return Err(error_response(Status::Conflict, "NotPending", "Device is not waiting for approval"));
+	}
+	device.approval = approval;
+
+	let query = update(device_dsl::device.find(device.device_id.clone())).set(device_dsl::approval.eq(approval));
+	if let Err(error) = database.run(move |conn| query.execute(conn)).await {
+		log::error!("Error while changing device approval: {:?}", error);
+		return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+	}
+	let tags = load_tags(database, vec![device.device_id.clone()]).await?.remove(&device.device_id).unwrap_or_default();
+	return Ok(Json::from(DeviceInfo::new(device, role, tags, bridge)));
+}
+
+/// Lets the pending device connect
+#[post("/<id>/approve")]
+async fn approve_device(database: MainDatabase, bridge: &State<DeviceBridge>, auth: AuthenticatedUser, id: &str) -> Result<Json<DeviceInfo>, ErrorResponse> {
+	return decide_approval(&database, bridge, &auth.user, id, DeviceApproval::Approved).await;
+}
+
+/// Refuses the pending device. It is told to unregister and removed when it tries to connect, or by the registration sweeper after the registration timeout
+#[post("/<id>/reject")]
+async fn reject_device(database: MainDatabase, bridge: &State<DeviceBridge>, auth: AuthenticatedUser, id: &str) -> Result<Json<DeviceInfo>, ErrorResponse> {
+	return decide_approval(&database, bridge, &auth.user, id, DeviceApproval::Rejected).await;
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PairingTokenInfo {
+	/// Hex encoded, given to the device which sends it in the first registration packet
+	pub token: String,
+	pub expires_at: DateTime<Utc>,
+}
+
+/// Creates a single-use token that lets a device register itself to the account of the user.
+/// With hex encoded `organization` given, the device is registered to the organization, which requires at least the operator role in it
+#[post("/pairing-token?<organization>")]
+async fn create_pairing_token(database: MainDatabase, auth: AuthenticatedUser, organization: Option<&str>) -> Result<Json<PairingTokenInfo>, ErrorResponse> {
+	let organization_id = match organization {
+		Some(organization) => {
+			let member = find_membership(&database, &auth.user, organization).await?;
+			if member.role < DeviceRole::Operator {
+				return Err(error_response(Status::Forbidden, "InsufficientRole", "Only operators and admins can register devices to the organization"));
+			}
+			Some(member.organization_id)
+		}
+		None => None,
+	};
+	let mut token = [0u8; 16];
+	rand::thread_rng().fill(&mut token);
+	let now = Utc::now();
+	let expires_at = now + PAIRING_TOKEN_LIFETIME;
+	let new_token = PairingToken {
+		token: Vec::from(token),
+		user_id: auth.user.user_id,
+		expires_at: expires_at.naive_utc(),
+		organization_id,
+	};
+	let inserted = database.run(move |conn| {
+		// Expired tokens can't be used anymore, so they are cleaned up here
+		diesel::delete(pairing_dsl::pairing_token.filter(pairing_dsl::expires_at.le(now.naive_utc()))).execute(conn)?;
+		return insert_into(pairing_dsl::pairing_token).values(new_token).execute(conn);
+	}).await;
+	match inserted {
+		Ok(_) => {
+			return Ok(Json::from(PairingTokenInfo {
+				token: hex::encode(token),
+				expires_at,
+			}));
+		}
+		Err(error) => {
+			log::error!("Error while creating pairing token: {:?}", error);
+			return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeletionResult {
+	/// Whether the device was connected and got notified about its removal
+	pub notified: bool,
+}
+
+/// Removes the device from the account of its owner
+#[delete("/<id>")]
+async fn delete_device(database: MainDatabase, bridge: &State<DeviceBridge>, auth: AuthenticatedUser, id: &str) -> Result<Json<DeletionResult>, ErrorResponse> {
+	let device = find_owned_device(&database, &auth.user, id).await?;
+	let device_id: [u8; 16] = device.device_id.try_into().unwrap();
+	match storage::delete_device(&database, bridge.storage_root(), device_id).await {
+		Ok(_) | Err(diesel::result::Error::NotFound) => {
+			let notified = bridge.notify_unregistered(device_id);
+			return Ok(Json::from(DeletionResult { notified }));
+		}
+		Err(error) => {
+			log::error!("Error while deleting device: {:?}", error);
+			return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+		}
+	}
+}
+
+/// Sent as a text message before the binary message with the image
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FrameMetadata {
+	/// Increases by one with every frame of the device, gaps mean dropped frames
+	pub sequence: u64,
+	pub timestamp: DateTime<Utc>,
+	/// Size of the image in bytes
+	pub size: usize,
+}
+
+/// Pushes live frames of the device over a WebSocket, each as a JSON [`FrameMetadata`] text message followed by a binary message with the image
+#[get("/<id>/live?<token>")]
+async fn live_websocket(websocket: WebSocket, database: MainDatabase, bridge: &State<DeviceBridge>, mut shutdown: Shutdown, auth: Result<AuthenticatedUser, AuthError>, id: &str, token: Option<&str>) -> Result<Channel<'static>, ErrorResponse> {
+	let user = resolve_user(&database, auth, id, token).await?;
+	let (device, _) = find_device(&database, &user, id, Some(DeviceRole::Viewer)).await?;
+	let device_id: [u8; 16] = device.device_id.try_into().unwrap();
+
+	let mut frames = bridge.subscribe_frames();
+	return Ok(websocket.channel(move |mut stream| Box::pin(async move {
+		loop {
+			select! {
+				received = frames.recv() => {
+					let frame = match received {
+						Ok(frame) => frame,
+						Err(RecvError::Lagged(count)) => {
+							log::debug!("WebSocket stream of device {:?} skipped {} frames", device_id, count);
+							continue;
+						}
+						Err(RecvError::Closed) => break,
+					};
+					if frame.device_id != device_id {
+						continue;
+					}
+					let metadata = FrameMetadata {
+						sequence: frame.sequence,
+						timestamp: frame.received_at,
+						size: frame.data.len(),
+					};
+					stream.send(Message::Text(json::to_string(&metadata).unwrap())).await?;
+					stream.send(Message::Binary(frame.data.clone())).await?;
+				}
+				message = stream.next() => {
+					match message {
+						Some(Ok(Message::Close(_))) | None => break,
+						// Clients are not expected to send anything else
+						Some(Ok(_)) => {}
+						Some(Err(err)) => return Err(err),
+					}
+				}
+				_ = &mut shutdown => break,
+			}
+		}
+		return Ok(());
+	})));
+}
+
+#[cfg(test)]
+mod tests {
+	use rocket::{futures::StreamExt, http::Header, local::asynchronous::Client};
+
+	use crate::{auth::AuthToken, device_connector::packets::{Message, UnregisterDevicePacket}, model::NewRecording, routes_common::Error, tests_common};
+	use super::*;
+
+	#[rocket::async_test]
+	async fn stream_requires_owner() {
+		let client = tests_common::create_local_async_client().await;
+		let user = tests_common::setup_user(&client).await;
+		let device = tests_common::setup_device(&client, &user).await;
+		let other_user = tests_common::setup_other_user(&client).await;
+		let url = format!("/device/{}/stream.mjpeg", hex::encode(&device.device_id));
+
+		let response = client.get(url.clone()).dispatch().await;
+		assert_eq!(response.status(), Status::Unauthorized);
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "MissingToken");
+
+		let other_token = tests_common::login(&client, &other_user).await;
+		let response = client.get(url.clone()).header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.status(), Status::NotFound);
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "DeviceNotFound");
+
+		let token = tests_common::login(&client, &user).await;
+		let response = client.get(url.clone()).header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		assert_eq!(response.content_type().unwrap().media_type().sub(), "x-mixed-replace");
+	}
+
+	#[rocket::async_test]
+	async fn stream_token_in_url() {
+		let client = tests_common::create_local_async_client().await;
+		let user = tests_common::setup_user(&client).await;
+		let device = tests_common::setup_device(&client, &user).await;
+		let token = tests_common::login(&client, &user).await;
+		let url = format!("/device/{}", hex::encode(&device.device_id));
+
+		// Login tokens would leak into logs and browser history
+		let response = client.get(format!("{}/stream.mjpeg?token={}", url, token)).dispatch().await;
+		assert_eq!(response.status(), Status::Unauthorized);
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "InvalidToken");
+
+		let response = client.post(format!("{}/stream-token", url)).header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		let stream_token = response.into_json::<StreamTokenResult>().await.unwrap().token;
+		let response = client.get(format!("{}/stream.mjpeg?token={}", url, stream_token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		assert_eq!(response.content_type().unwrap().media_type().sub(), "x-mixed-replace");
+
+		// Stream tokens don't work anywhere else
+		let response = client.get(url.clone()).header(tests_common::bearer(&stream_token)).dispatch().await;
+		assert_eq!(response.status(), Status::Unauthorized);
+		let other_device = StreamToken::new(&user.user_id, &[7; 16], &hex::decode(AuthToken::decode(&token).unwrap().session).unwrap(), Utc::now() + STREAM_TOKEN_LIFETIME);
+		let response = client.get(format!("{}/stream.mjpeg?token={}", url, other_device.encode())).dispatch().await;
+		assert_eq!(response.status(), Status::Unauthorized);
+	}
+
+	#[rocket::async_test]
+	async fn offline_status() {
+		let client = tests_common::create_local_async_client().await;
+		let user = tests_common::setup_user(&client).await;
+		let device = tests_common::setup_device(&client, &user).await;
+		let token = tests_common::login(&client, &user).await;
+
+		let response = client.get(format!("/device/{}/status", hex::encode(&device.device_id))).header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		let status = response.into_json::<DeviceStatus>().await.unwrap();
+		assert!(!status.online);
+		assert_eq!(status.last_seen, None);
+		assert_eq!(status.connected_since, None);
+	}
+
+	#[rocket::async_test]
+	async fn delete_test() {
+		let client = tests_common::create_local_async_client().await;
+		let user = tests_common::setup_user(&client).await;
+		let device = tests_common::setup_device(&client, &user).await;
+		let other_user = tests_common::setup_other_user(&client).await;
+		let url = format!("/device/{}", hex::encode(&device.device_id));
+
+		let other_token = tests_common::login(&client, &other_user).await;
+		let response = client.delete(url.clone()).header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.status(), Status::NotFound);
+
+		let token = tests_common::login(&client, &user).await;
+		let response = client.delete(url.clone()).header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		assert!(!response.into_json::<DeletionResult>().await.unwrap().notified);
+
+		let response = client.delete(url).header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::NotFound);
+	}
+
+	#[rocket::async_test]
+	async fn delete_connected_device() {
+		let client = tests_common::create_local_async_client().await;
+		let user = tests_common::setup_user(&client).await;
+		let device = tests_common::setup_device(&client, &user).await;
+		let token = tests_common::login(&client, &user).await;
+		let bridge = client.rocket().state::<DeviceBridge>().unwrap();
+		let (mut connection, session_id) = tests_common::connect_device(&client, &device).await;
+
+		let response = client.delete(format!("/device/{}", hex::encode(&device.device_id))).header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		assert!(response.into_json::<DeletionResult>().await.unwrap().notified);
+
+		let notice = connection.next().await.unwrap().unwrap();
+		assert_eq!(notice.header.session_id, session_id);
+		assert!(!notice.header.is_response);
+		assert!(matches!(notice.message, Message::UnregisterDevice(UnregisterDevicePacket { success: 0 })));
+		// The connection is closed even if the device never answers
+		assert!(connection.next().await.is_none());
+		assert_eq!(bridge.stats().connected_devices, 0);
+	}
+
+	#[rocket::async_test]
+	async fn websocket_requires_token() {
+		let client = tests_common::create_local_async_client().await;
+		let user = tests_common::setup_user(&client).await;
+		let device = tests_common::setup_device(&client, &user).await;
+		let response = client.get(format!("/device/{}/live?token=invalid", hex::encode(&device.device_id)))
+			.header(Header::new("Connection", "Upgrade"))
+			.header(Header::new("Upgrade", "websocket"))
+			.header(Header::new("Sec-WebSocket-Version", "13"))
+			.header(Header::new("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="))
+			.dispatch().await;
+		assert_eq!(response.status(), Status::Unauthorized);
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "InvalidToken");
+	}
+
+	#[rocket::async_test]
+	async fn list_and_get() {
+		let client = tests_common::create_local_async_client().await;
+		let user = tests_common::setup_user(&client).await;
+		let device = tests_common::setup_device(&client, &user).await;
+		let other_user = tests_common::setup_other_user(&client).await;
+
+		let token = tests_common::login(&client, &user).await;
+		let response = client.get("/device").header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		let devices = response.into_json::<Vec<DeviceInfo>>().await.unwrap();
+		assert_eq!(devices.len(), 1);
+		assert_eq!(devices[0].device_id, hex::encode(&device.device_id));
+		assert_eq!(devices[0].mac_address, "020202020202");
+		assert_eq!(devices[0].registration_stage, RegistrationStage::Completed);
+		assert!(!devices[0].mac_conflict);
+		assert!(!devices[0].status.online);
+
+		let response = client.get(format!("/device/{}", devices[0].device_id)).header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		assert_eq!(response.into_json::<DeviceInfo>().await.unwrap().device_id, devices[0].device_id);
+
+		let other_token = tests_common::login(&client, &other_user).await;
+		let response = client.get("/device").header(tests_common::bearer(&other_token)).dispatch().await;
+		assert!(response.into_json::<Vec<DeviceInfo>>().await.unwrap().is_empty());
+		let response = client.get(format!("/device/{}", devices[0].device_id)).header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.status(), Status::NotFound);
+	}
+
+	#[rocket::async_test]
+	async fn rename_test() {
+		let client = tests_common::create_local_async_client().await;
+		let user = tests_common::setup_user(&client).await;
+		let device = tests_common::setup_device(&client, &user).await;
+		let token = tests_common::login(&client, &user).await;
+		let url = format!("/device/{}", hex::encode(&device.device_id));
+
+		let response = client.put(format!("{}/name", url)).json(&RenameDeviceData { name: String::from(" Front door ") })
+			.header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		assert_eq!(response.into_json::<DeviceInfo>().await.unwrap().name.as_deref(), Some("Front door"));
+
+		let response = client.put(format!("{}/name", url)).json(&RenameDeviceData { name: "a".repeat(65) })
+			.header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::BadRequest);
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "InvalidName");
+
+		let response = client.get(url).header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.into_json::<DeviceInfo>().await.unwrap().name.as_deref(), Some("Front door"));
+	}
+
+	#[rocket::async_test]
+	async fn update_metadata() {
+		let client = tests_common::create_local_async_client().await;
+		let user = tests_common::setup_user(&client).await;
+		let device = tests_common::setup_device(&client, &user).await;
+		let token = tests_common::login(&client, &user).await;
+		let url = format!("/device/{}", hex::encode(&device.device_id));
+
+		let response = client.patch(url.clone()).json(&UpdateDeviceData {
+			name: Some(String::from("Garage")),
+			location: Some(String::from("Above the door")),
+			tags: Some(vec![String::from("outdoor"), String::from(" garage "), String::from("outdoor")]),
+			timezone: Some(String::from("Europe/Warsaw")),
+		}).header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		let info = response.into_json::<DeviceInfo>().await.unwrap();
+		assert_eq!(info.name.as_deref(), Some("Garage"));
+		assert_eq!(info.location.as_deref(), Some("Above the door"));
+		assert_eq!(info.tags, vec!["garage", "outdoor"]);
+		assert_eq!(info.timezone.as_deref(), Some("Europe/Warsaw"));
+
+		// Missing fields are kept, empty ones are cleared
+		let response = client.patch(url.clone()).json(&UpdateDeviceData {
+			location: Some(String::new()),
+			..Default::default()
+		}).header(tests_common::bearer(&token)).dispatch().await;
+		let info = response.into_json::<DeviceInfo>().await.unwrap();
+		assert_eq!(info.name.as_deref(), Some("Garage"));
+		assert_eq!(info.location, None);
+		assert_eq!(info.tags, vec!["garage", "outdoor"]);
+
+		let response = client.patch(url.clone()).json(&UpdateDeviceData {
+			timezone: Some(String::from("Mars/Olympus_Mons")),
+			..Default::default()
+		}).header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::BadRequest);
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "InvalidTimezone");
+
+		let response = client.get("/device?tag=outdoor&tag=garage").header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.into_json::<Vec<DeviceInfo>>().await.unwrap().len(), 1);
+		let response = client.get("/device?tag=indoor").header(tests_common::bearer(&token)).dispatch().await;
+		assert!(response.into_json::<Vec<DeviceInfo>>().await.unwrap().is_empty());
+
+		let response = client.delete(url).header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+	}
+
+	#[rocket::async_test]
+	async fn pairing_token_test() {
+		let client = tests_common::create_local_async_client().await;
+		let user = tests_common::setup_user(&client).await;
+
+		let response = client.post("/device/pairing-token").dispatch().await;
+		assert_eq!(response.status(), Status::Unauthorized);
+
+		let token = tests_common::login(&client, &user).await;
+		let response = client.post("/device/pairing-token").header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		let pairing = response.into_json::<PairingTokenInfo>().await.unwrap();
+		assert!(pairing.expires_at > Utc::now());
+
+		let database = MainDatabase::get_one(client.rocket()).await.unwrap();
+		let pairing_token = hex::decode(pairing.token).unwrap();
+		let owner = database.run(move |conn| PairingToken::redeem(conn, pairing_token, Utc::now().naive_utc())).await.unwrap();
+		assert_eq!(owner.user_id, user.user_id);
+	}
+
+	#[rocket::async_test]
+	async fn approval_test() {
+		let client = tests_common::create_local_async_client().await;
+		let user = tests_common::setup_user(&client).await;
+		let device = tests_common::setup_device(&client, &user).await;
+		let token = tests_common::login(&client, &user).await;
+		let url = format!("/device/{}", hex::encode(&device.device_id));
+
+		let response = client.post(format!("{}/approve", url)).header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::Conflict);
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "NotPending");
+
+		let database = MainDatabase::get_one(client.rocket()).await.unwrap();
+		let query = update(device_dsl::device.find(device.device_id.clone())).set(device_dsl::approval.eq(DeviceApproval::Pending));
+		database.run(move |conn| query.execute(conn)).await.unwrap();
+		let response = client.get(url.clone()).header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.into_json::<DeviceInfo>().await.unwrap().approval, DeviceApproval::Pending);
+
+		let response = client.post(format!("{}/reject", url)).header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		assert_eq!(response.into_json::<DeviceInfo>().await.unwrap().approval, DeviceApproval::Rejected);
+		let response = client.post(format!("{}/approve", url)).header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::Conflict);
+	}
+
+	#[rocket::async_test]
+	async fn registrations_test() {
+		let client = tests_common::create_local_async_client().await;
+		let user = tests_common::setup_user(&client).await;
+		let device = tests_common::setup_device(&client, &user).await;
+		let token = tests_common::login(&client, &user).await;
+
+		let response = client.get("/device/registrations").header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		assert!(response.into_json::<Vec<RegistrationInfo>>().await.unwrap().is_empty());
+
+		let database = MainDatabase::get_one(client.rocket()).await.unwrap();
+		let query = update(device_dsl::device.find(device.device_id.clone())).set(device_dsl::registration_first_stage.eq(true));
+		database.run(move |conn| query.execute(conn)).await.unwrap();
+		let response = client.get("/device/registrations").header(tests_common::bearer(&token)).dispatch().await;
+		let registrations = response.into_json::<Vec<RegistrationInfo>>().await.unwrap();
+		assert_eq!(registrations.len(), 1);
+		assert_eq!(registrations[0].device_id, hex::encode(&device.device_id));
+		assert!(registrations[0].expires_at > registrations[0].created_at);
+	}
+
+	#[rocket::async_test]
+	async fn recordings_require_operator() {
+		let storage_root = std::env::temp_dir().join(format!("camera-server-test-{}", hex::encode(rand::random::<[u8; 8]>())));
+		let rocket = tests_common::create_test_rocket();
+		let figment = rocket.figment().clone().merge(("device_bridge.storage_root", storage_root.clone()));
+		let client = Client::tracked(rocket.configure(figment)).await.unwrap();
+		let user = tests_common::setup_user(&client).await;
+		let device = tests_common::setup_device(&client, &user).await;
+		let other_user = tests_common::setup_other_user(&client).await;
+		let token = tests_common::login(&client, &user).await;
+		let other_token = tests_common::login(&client, &other_user).await;
+
+		let path = format!("{}/2024-07-03/14-05-09.000-0.jpg", hex::encode(&device.device_id));
+		std::fs::create_dir_all(storage_root.join(&path).parent().unwrap()).unwrap();
+		std::fs::write(storage_root.join(&path), [0xff, 0xd8, 0xff, 0xd9]).unwrap();
+		let database = MainDatabase::get_one(client.rocket()).await.unwrap();
+		let recording = NewRecording {
+			device_id: device.device_id.clone(),
+			captured_at: Utc::now().naive_utc(),
+			size: 4,
+			path,
+		};
+		let access = DeviceAccess {
+			device_id: device.device_id.clone(),
+			user_id: other_user.user_id.clone(),
+			role: DeviceRole::Viewer,
+			created_at: Utc::now().naive_utc(),
+		};
+		database.run(move |conn| {
+			insert_into(recording_dsl::recording).values(recording).execute(conn)?;
+			return insert_into(access_dsl::device_access).values(access).execute(conn);
+		}).await.unwrap();
+		let url = format!("/device/{}/recordings", hex::encode(&device.device_id));
+
+		let response = client.get(url.clone()).header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.status(), Status::Forbidden);
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "InsufficientRole");
+
+		let query = update(access_dsl::device_access).set(access_dsl::role.eq(DeviceRole::Operator));
+		database.run(move |conn| query.execute(conn)).await.unwrap();
+		let response = client.get(url.clone()).header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		let recordings = response.into_json::<Vec<RecordingInfo>>().await.unwrap();
+		assert_eq!(recordings.len(), 1);
+		assert_eq!(recordings[0].size, 4);
+
+		let response = client.get(format!("{}/{}", url, recordings[0].recording_id)).header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		assert_eq!(response.content_type(), Some(ContentType::JPEG));
+		assert_eq!(response.into_bytes().await.unwrap(), vec![0xff, 0xd8, 0xff, 0xd9]);
+
+		let response = client.get(format!("{}/{}", url, recordings[0].recording_id + 1)).header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::NotFound);
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "RecordingNotFound");
+		std::fs::remove_dir_all(&storage_root).unwrap();
+	}
+}
@@ -1,5 +1,4 @@
-#![feature(assert_matches)]
-#![feature(generic_arg_infer)]
+#![allow(clippy::needless_return)]
 
 use device_connector::DeviceBridge;
 use rocket::launch;
@@ -8,6 +7,12 @@ use rocket_sync_db_pools::database;
 mod schema;
 mod model;
 mod user_routes;
+mod device_routes;
+mod transfer_routes;
+mod access_routes;
+mod organization_routes;
+mod admin_routes;
+mod routes_common;
 mod auth;
 mod device_connector;
 
@@ -23,8 +28,15 @@ fn rocket() -> _ {
 
     rocket::build()
         .mount("/user", user_routes::routes())
+        .mount("/device", device_routes::routes())
+        .mount("/device", transfer_routes::routes())
+        .mount("/device", access_routes::routes())
+        .mount("/organization", organization_routes::routes())
+        .mount("/admin", admin_routes::routes())
+        .register("/", auth::catchers())
         .attach(MainDatabase::fairing())
-		.attach(DeviceBridge::fairing(3333))
+        .attach(admin_routes::fairing())
+		.attach(DeviceBridge::fairing())
         /*.mount("/swagger-ui", make_swagger_ui(&SwaggerUIConfig {
             url: "../openapi.json".to_owned(),
             ..Default::default()
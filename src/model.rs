@@ -1,7 +1,10 @@
 use crate::schema::*;
-use diesel::prelude::*;
+use chrono::NaiveDateTime;
+use diesel::{backend::Backend, deserialize::{self, FromSql, FromSqlRow}, expression::AsExpression, prelude::*, serialize::{self, IsNull, Output, ToSql}, sql_types::Text, sqlite::Sqlite};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-#[derive(Debug, PartialEq, Queryable, Identifiable, Selectable, Insertable)]
+#[derive(Debug, Clone, PartialEq, Queryable, Identifiable, Selectable, Insertable)]
 #[diesel(table_name = users)]
 #[diesel(primary_key(user_id))]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
@@ -10,12 +13,24 @@ pub struct User {
 	pub username: String,
 	pub password: String,
 	pub email: String,
+	/// Can use the `/admin` routes
+	pub is_admin: bool,
+	/// Set by an administrator, disabled users can't log in and their tokens are rejected
+	pub disabled: bool,
+}
+
+impl User {
+	pub fn find_by_name(conn: &mut SqliteConnection, name: &str) -> QueryResult<User> {
+		return users::table.filter(users::username.eq(name)).first::<User>(conn);
+	}
 }
 
 #[derive(Debug, Clone, PartialEq, Queryable, Identifiable, Selectable, Associations, Insertable, AsChangeset)]
 #[diesel(table_name = device)]
 #[diesel(primary_key(device_id))]
 #[diesel(belongs_to(User))]
+// Updates always write the whole row, so clearing an optional field has to store NULL
+#[diesel(treat_none_as_null = true)]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
 pub struct Device {
 	pub device_id: Vec<u8>,
@@ -23,4 +38,400 @@ pub struct Device {
 	pub auth_key: Vec<u8>,
 	pub registration_first_stage: bool,
 	pub user_id: Vec<u8>,
+	/// Time of the last heartbeat of the device, updated when its session ends
+	pub last_seen: Option<NaiveDateTime>,
+	/// Chosen by the owner, `None` until the device is named
+	pub name: Option<String>,
+	/// Free-form description of where the device is placed
+	pub location: Option<String>,
+	/// IANA name, like `Europe/Warsaw`
+	pub timezone: Option<String>,
+	/// Pairing token used in the first registration stage, which the device has to send again in the second stage.
+	/// Cleared once registration finishes
+	pub registration_token: Option<Vec<u8>>,
+	pub approval: DeviceApproval,
+	/// Time of the first registration stage
+	pub created_at: NaiveDateTime,
+	/// Set when the MAC address was already registered under another user when the device registered,
+	/// which can mean the device is cloned or stolen
+	pub mac_conflict: bool,
+	/// Organization owning the device collectively. Its members get access through their membership,
+	/// and `user_id` only records who registered the device
+	pub organization_id: Option<Vec<u8>>,
+}
+
+/// Result of [`Device::insert_paired`]
+pub enum Pairing {
+	/// No device with the MAC address was registered
+	New(Device),
+	/// Device of the same user with the same MAC address, probably factory reset, was moved back to the first registration stage
+	Replaced(Device),
+	/// Device was inserted with `mac_conflict` set, because its MAC address is registered under other users
+	Conflicting {
+		device: Device,
+		other_owners: Vec<Vec<u8>>,
+	},
+}
+
+/// Decision of the owner about a newly registered device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsExpression, FromSqlRow, Serialize, Deserialize)]
+#[diesel(sql_type = Text)]
+pub enum DeviceApproval {
+	/// Registered, but can't connect until the owner approves it
+	Pending,
+	Approved,
+	/// Told to unregister the next time it connects
+	Rejected,
+}
+
+impl DeviceApproval {
+	fn as_str(self) -> &'static str {
+		match self {
+			DeviceApproval::Pending => "pending",
+			DeviceApproval::Approved => "approved",
+			DeviceApproval::Rejected => "rejected",
+		}
+	}
+}
+
+impl ToSql<Text, Sqlite> for DeviceApproval {
+	fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Sqlite>) -> serialize::Result {
+		out.set_value(self.as_str());
+		return Ok(IsNull::No);
+	}
+}
+
+impl FromSql<Text, Sqlite> for DeviceApproval {
+	fn from_sql(bytes: <Sqlite as Backend>::RawValue<'_>) -> deserialize::Result<Self> {
+		match <String as FromSql<Text, Sqlite>>::from_sql(bytes)?.as_str() {
+			"pending" => Ok(DeviceApproval::Pending),
+			"approved" => Ok(DeviceApproval::Approved),
+			"rejected" => Ok(DeviceApproval::Rejected),
+			other => Err(format!("Unknown device approval {}", other).into()),
+		}
+	}
+}
+
+/// Access given by the owner to another user, or to a member of the organization owning the device. Each role includes the rights of the previous ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, AsExpression, FromSqlRow, Serialize, Deserialize)]
+#[diesel(sql_type = Text)]
+pub enum DeviceRole {
+	/// Can see the device and watch it live
+	Viewer,
+	/// Can also play back recordings and change device settings
+	Operator,
+	/// Can also approve the device and share it with other users as viewer or operator
+	Admin,
+}
+
+impl DeviceRole {
+	fn as_str(self) -> &'static str {
+		match self {
+			DeviceRole::Viewer => "viewer",
+			DeviceRole::Operator => "operator",
+			DeviceRole::Admin => "admin",
+		}
+	}
+}
+
+impl ToSql<Text, Sqlite> for DeviceRole {
+	fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Sqlite>) -> serialize::Result {
+		out.set_value(self.as_str());
+		return Ok(IsNull::No);
+	}
+}
+
+impl FromSql<Text, Sqlite> for DeviceRole {
+	fn from_sql(bytes: <Sqlite as Backend>::RawValue<'_>) -> deserialize::Result<Self> {
+		match <String as FromSql<Text, Sqlite>>::from_sql(bytes)?.as_str() {
+			"viewer" => Ok(DeviceRole::Viewer),
+			"operator" => Ok(DeviceRole::Operator),
+			"admin" => Ok(DeviceRole::Admin),
+			other => Err(format!("Unknown device role {}", other).into()),
+		}
+	}
+}
+
+impl Device {
+	/// Registers the device to the user that created the pairing token, using the token up.
+	/// Tokens created for an organization register the device to the organization instead.
+	/// A device of the same owner with the same MAC address is reused instead of inserting a new one, keeping its ID, metadata and recordings.
+	/// Returns `NotFound` if the token is not valid. Nothing is changed if inserting fails
+	pub fn insert_paired(conn: &mut SqliteConnection, token: Vec<u8>, device: Device, now: NaiveDateTime) -> QueryResult<Pairing> {
+		return conn.transaction(|conn| {
+			let token = PairingToken::redeem(conn, token, now)?;
+			let same_mac = device::table
+				.filter(device::mac_address.eq(device.mac_address.clone()))
+				.order(device::created_at.desc())
+				.load::<Device>(conn)?;
+			let same_owner = |existing: &Device| match &token.organization_id {
+				Some(organization_id) => existing.organization_id.as_ref() == Some(organization_id),
+				None => existing.organization_id.is_none() && existing.user_id == token.user_id,
+			};
+
+			if let Some(existing) = same_mac.iter().find(|existing| same_owner(existing)) {
+				// The new auth key has to be confirmed and the owner has to approve the device again
+				let replaced = Device {
+					auth_key: device.auth_key,
+					registration_first_stage: true,
+					approval: DeviceApproval::Pending,
+					created_at: device.created_at,
+					registration_token: Some(token.token),
+					..existing.clone()
+				};
+				diesel::update(device::table.find(replaced.device_id.clone())).set(&replaced).execute(conn)?;
+				// An offer made before the reset shouldn't hand over the device that has to be approved again
+				diesel::delete(device_transfer::table.find(replaced.device_id.clone())).execute(conn)?;
+				return Ok(Pairing::Replaced(replaced));
+			}
+
+			let mut other_owners = same_mac.into_iter().map(|existing| existing.user_id).collect::<Vec<_>>();
+			other_owners.sort();
+			other_owners.dedup();
+			let device = Device {
+				user_id: token.user_id,
+				organization_id: token.organization_id,
+				mac_conflict: !other_owners.is_empty(),
+				registration_token: Some(token.token),
+				..device
+			};
+			diesel::insert_into(device::table).values(device.clone()).execute(conn)?;
+			if other_owners.is_empty() {
+				return Ok(Pairing::New(device));
+			}
+			return Ok(Pairing::Conflicting { device, other_owners });
+		});
+	}
+
+	/// IDs of devices that entered the first registration stage before `created_before` and never finished it or were rejected.
+	/// Devices moved back to the first stage by [`Device::insert_paired`] are left out if they were ever connected or have recordings,
+	/// so a reset device that didn't finish registering again keeps its history. Rejected devices with history are kept for the same reason,
+	/// until their owner deletes them
+	pub fn abandoned_registrations(conn: &mut SqliteConnection, created_before: NaiveDateTime) -> QueryResult<Vec<Vec<u8>>> {
+		let recordings = recording::table.filter(recording::device_id.eq(device::device_id));
+		return device::table
+			.filter(device::registration_first_stage.eq(true).or(device::approval.eq(DeviceApproval::Rejected)))
+			.filter(device::created_at.lt(created_before))
+			.filter(device::last_seen.is_null())
+			.filter(diesel::dsl::not(diesel::dsl::exists(recordings)))
+			.select(device::device_id)
+			.load::<Vec<u8>>(conn);
+	}
+
+	/// Deletes the device together with its tags, shares, pending transfer and the index of its recordings.
+	/// Returns `NotFound` if the device does not exist
+	pub fn delete(conn: &mut SqliteConnection, id: Vec<u8>) -> QueryResult<()> {
+		return conn.transaction(|conn| {
+			diesel::delete(recording::table.filter(recording::device_id.eq(id.clone()))).execute(conn)?;
+			diesel::delete(device_tag::table.filter(device_tag::device_id.eq(id.clone()))).execute(conn)?;
+			diesel::delete(device_transfer::table.find(id.clone())).execute(conn)?;
+			diesel::delete(device_access::table.filter(device_access::device_id.eq(id.clone()))).execute(conn)?;
+			match diesel::delete(device::table.find(id)).execute(conn)? {
+				0 => Err(diesel::result::Error::NotFound),
+				_ => Ok(()),
+			}
+		});
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Queryable, Selectable, Insertable, Associations)]
+#[diesel(table_name = device_tag)]
+#[diesel(belongs_to(Device))]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct DeviceTag {
+	pub device_id: Vec<u8>,
+	pub tag: String,
+}
+
+/// Role of a user that doesn't own the device
+#[derive(Debug, Clone, PartialEq, Queryable, Identifiable, Selectable, Insertable, AsChangeset, Associations)]
+#[diesel(table_name = device_access)]
+#[diesel(primary_key(device_id, user_id))]
+#[diesel(belongs_to(Device))]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct DeviceAccess {
+	pub device_id: Vec<u8>,
+	pub user_id: Vec<u8>,
+	pub role: DeviceRole,
+	pub created_at: NaiveDateTime,
+}
+
+/// Single-use code that lets a device register itself to the account of `user_id`, or to the organization if set
+#[derive(Debug, Clone, PartialEq, Queryable, Identifiable, Selectable, Insertable)]
+#[diesel(table_name = pairing_token)]
+#[diesel(primary_key(token))]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct PairingToken {
+	pub token: Vec<u8>,
+	pub user_id: Vec<u8>,
+	pub expires_at: NaiveDateTime,
+	pub organization_id: Option<Vec<u8>>,
+}
+
+impl PairingToken {
+	/// Deletes the token if it is still valid at `now` and returns it.
+	/// Returns `NotFound` for unknown, expired and already used tokens
+	pub fn redeem(conn: &mut SqliteConnection, token: Vec<u8>, now: NaiveDateTime) -> QueryResult<PairingToken> {
+		return conn.transaction(|conn| {
+			let found = pairing_token::table.find(token.clone()).filter(pairing_token::expires_at.gt(now)).first::<PairingToken>(conn)?;
+			diesel::delete(pairing_token::table.find(token)).execute(conn)?;
+			Ok(found)
+		});
+	}
+}
+
+/// Long-lived token exchanged for new access tokens. Only the SHA-256 hash of the token is stored.
+/// Every refresh replaces the token with a new one in the same session, deleting the session's rows logs it out
+#[derive(Debug, Clone, PartialEq, Queryable, Identifiable, Selectable, Insertable)]
+#[diesel(table_name = refresh_token)]
+#[diesel(primary_key(token_hash))]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct RefreshToken {
+	pub token_hash: Vec<u8>,
+	pub session_id: Vec<u8>,
+	pub user_id: Vec<u8>,
+	pub created_at: NaiveDateTime,
+	pub expires_at: NaiveDateTime,
+	/// Already exchanged for a newer token, kept until expiry to detect reuse
+	pub rotated: bool,
+}
+
+impl RefreshToken {
+	pub fn hash(token: &[u8]) -> Vec<u8> {
+		return Sha256::digest(token).to_vec();
+	}
+
+	/// Marks the token with `token_hash` as rotated and stores `new_token_hash` in its session instead.
+	/// Returns `NotFound` for unknown and expired tokens. A token that was already rotated has leaked,
+	/// so its whole session is revoked and `NotFound` is returned as well
+	pub fn rotate(conn: &mut SqliteConnection, token_hash: Vec<u8>, new_token_hash: Vec<u8>, now: NaiveDateTime, expires_at: NaiveDateTime) -> QueryResult<RefreshToken> {
+		let found = refresh_token::table.find(token_hash.clone()).filter(refresh_token::expires_at.gt(now)).first::<RefreshToken>(conn)?;
+		if found.rotated {
+			RefreshToken::revoke_session(conn, found.session_id)?;
+			return Err(diesel::result::Error::NotFound);
+		}
+		let replacement = RefreshToken {
+			token_hash: new_token_hash,
+			session_id: found.session_id,
+			user_id: found.user_id,
+			created_at: now,
+			expires_at,
+			rotated: false,
+		};
+		return conn.transaction(|conn| {
+			let updated = diesel::update(refresh_token::table.find(token_hash).filter(refresh_token::rotated.eq(false)))
+				.set(refresh_token::rotated.eq(true))
+				.execute(conn)?;
+			if updated == 0 {
+				// Rotated concurrently
+				return Err(diesel::result::Error::NotFound);
+			}
+			diesel::insert_into(refresh_token::table).values(&replacement).execute(conn)?;
+			Ok(replacement)
+		});
+	}
+
+	pub fn revoke_session(conn: &mut SqliteConnection, session_id: Vec<u8>) -> QueryResult<usize> {
+		return diesel::delete(refresh_token::table.filter(refresh_token::session_id.eq(session_id))).execute(conn);
+	}
+
+	/// Logs the user out of all sessions
+	pub fn revoke_user(conn: &mut SqliteConnection, user_id: Vec<u8>) -> QueryResult<usize> {
+		return diesel::delete(refresh_token::table.filter(refresh_token::user_id.eq(user_id))).execute(conn);
+	}
+
+	/// Whether the session still has a refresh token, and so wasn't logged out
+	pub fn session_active(conn: &mut SqliteConnection, session_id: Vec<u8>, user_id: Vec<u8>) -> QueryResult<bool> {
+		let query = refresh_token::table
+			.filter(refresh_token::session_id.eq(session_id))
+			.filter(refresh_token::user_id.eq(user_id));
+		return diesel::select(diesel::dsl::exists(query)).get_result(conn);
+	}
+}
+
+/// Group of users owning devices collectively
+#[derive(Debug, Clone, PartialEq, Queryable, Identifiable, Selectable, Insertable)]
+#[diesel(table_name = organization)]
+#[diesel(primary_key(organization_id))]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct Organization {
+	pub organization_id: Vec<u8>,
+	pub name: String,
+	pub created_at: NaiveDateTime,
+}
+
+/// Membership of a user in an organization. The role applies to all devices of the organization,
+/// and admins can also manage members and have full control over the devices
+#[derive(Debug, Clone, PartialEq, Queryable, Identifiable, Selectable, Insertable, Associations)]
+#[diesel(table_name = organization_member)]
+#[diesel(primary_key(organization_id, user_id))]
+#[diesel(belongs_to(Organization))]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct OrganizationMember {
+	pub organization_id: Vec<u8>,
+	pub user_id: Vec<u8>,
+	pub role: DeviceRole,
+	pub created_at: NaiveDateTime,
+}
+
+/// Offer of the device owner to give the device to another user
+#[derive(Debug, Clone, PartialEq, Queryable, Identifiable, Selectable, Insertable)]
+#[diesel(table_name = device_transfer)]
+#[diesel(primary_key(device_id))]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct DeviceTransfer {
+	pub device_id: Vec<u8>,
+	pub from_user_id: Vec<u8>,
+	pub to_user_id: Vec<u8>,
+	pub created_at: NaiveDateTime,
+	pub expires_at: NaiveDateTime,
+}
+
+impl DeviceTransfer {
+	/// Gives the device to `recipient` and removes the transfer.
+	/// Returns `NotFound` if `recipient` has no transfer of the device valid at `now`, or the device changed its owner since the transfer was offered
+	pub fn accept(conn: &mut SqliteConnection, device_id: Vec<u8>, recipient: Vec<u8>, now: NaiveDateTime) -> QueryResult<Device> {
+		return conn.transaction(|conn| {
+			let transfer = device_transfer::table
+				.find(device_id.clone())
+				.filter(device_transfer::to_user_id.eq(recipient))
+				.filter(device_transfer::expires_at.gt(now))
+				.first::<DeviceTransfer>(conn)?;
+			let updated = diesel::update(device::table.find(device_id.clone()).filter(device::user_id.eq(transfer.from_user_id)))
+				.set(device::user_id.eq(transfer.to_user_id.clone()))
+				.execute(conn)?;
+			if updated == 0 {
+				return Err(diesel::result::Error::NotFound);
+			}
+			diesel::delete(device_transfer::table.find(device_id.clone())).execute(conn)?;
+			// The new owner doesn't need a share anymore. Other shares stay, the new owner can revoke them
+			diesel::delete(device_access::table.find((device_id.clone(), transfer.to_user_id.clone()))).execute(conn)?;
+			device::table.find(device_id).first::<Device>(conn)
+		});
+	}
+}
+
+/// Frame stored on disk, `path` is relative to the storage root
+#[derive(Debug, Clone, PartialEq, Insertable)]
+#[diesel(table_name = recording)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewRecording {
+	pub device_id: Vec<u8>,
+	pub captured_at: NaiveDateTime,
+	pub size: i32,
+	pub path: String,
+}
+
+/// Frame stored on disk, `path` is relative to the storage root
+#[derive(Debug, Clone, PartialEq, Queryable, Identifiable, Selectable, Associations)]
+#[diesel(table_name = recording)]
+#[diesel(primary_key(recording_id))]
+#[diesel(belongs_to(Device))]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct Recording {
+	pub recording_id: i32,
+	pub device_id: Vec<u8>,
+	pub captured_at: NaiveDateTime,
+	pub size: i32,
+	pub path: String,
 }
\ No newline at end of file
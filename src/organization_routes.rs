@@ -0,0 +1,383 @@
+use chrono::{DateTime, Utc};
+use diesel::{insert_into, replace_into, Connection, ExpressionMethods, QueryDsl, RunQueryDsl};
+use rand::Rng;
+use rocket::{delete, get, post, put, http::Status, routes, serde::json::Json, Route};
+use serde::{Deserialize, Serialize};
+
+use crate::{access_routes::find_user, auth::AuthenticatedUser, model::{DeviceRole, Organization, OrganizationMember, User}, routes_common::{error_response, ErrorResponse}, MainDatabase};
+use crate::schema::organization::dsl as organization_dsl;
+use crate::schema::organization_member::dsl as member_dsl;
+use crate::schema::users::dsl as users_dsl;
+
+/// Longest allowed organization name, in characters
+const MAX_ORGANIZATION_NAME_LENGTH: usize = 64;
+
+pub fn routes() -> Vec<Route> {
+	return routes![
+		list_organizations,
+		create_organization,
+		list_members,
+		set_member,
+		remove_member
+	];
+}
+
+/// Finds the membership of `user` in the organization with hex encoded `id`.
+/// Organizations the user isn't a member of are reported as nonexistent
+pub async fn find_membership(database: &MainDatabase, user: &User, id: &str) -> Result<OrganizationMember, ErrorResponse> {
+	let organization_id = match hex::decode(id) {
+		Ok(organization_id) if organization_id.len() == 16 => organization_id,
+		_ => {
+			return Err(error_response(Status::NotFound, "OrganizationNotFound", "Organization with specified ID does not exist"));
+		}
+	};
+	let query = member_dsl::organization_member.find((organization_id, user.user_id.clone()));
+	match database.run(move |conn| query.first::<OrganizationMember>(conn)).await {
+		Ok(member) => {
+			return Ok(member);
+		}
+		Err(diesel::result::Error::NotFound) => {
+			return Err(error_response(Status::NotFound, "OrganizationNotFound", "Organization with specified ID does not exist"));
+		}
+		Err(error) => {
+			log::error!("Error while retrieving organization membership: {:?}", error);
+			return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+		}
+	}
+}
+
+/// Finds the membership of `user` and checks that the user is an admin of the organization
+async fn find_admin_membership(database: &MainDatabase, user: &User, id: &str) -> Result<OrganizationMember, ErrorResponse> {
+	let member = find_membership(database, user, id).await?;
+	if member.role != DeviceRole::Admin {
+		return Err(error_response(Status::Forbidden, "InsufficientRole", "Only admins can manage members of the organization"));
+	}
+	return Ok(member);
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OrganizationInfo {
+	/// Hex encoded, used in organization URLs and when creating pairing tokens
+	pub organization_id: String,
+	pub name: String,
+	/// Role of the user in the organization
+	pub role: DeviceRole,
+	pub created_at: DateTime<Utc>,
+}
+
+/// Lists organizations the user is a member of
+#[get("/")]
+async fn list_organizations(database: MainDatabase, auth: AuthenticatedUser) -> Result<Json<Vec<OrganizationInfo>>, ErrorResponse> {
+	let query = member_dsl::organization_member
+		.inner_join(organization_dsl::organization)
+		.filter(member_dsl::user_id.eq(auth.user.user_id))
+		.order(organization_dsl::name);
+	match database.run(move |conn| query.load::<(OrganizationMember, Organization)>(conn)).await {
+		Ok(memberships) => {
+			return Ok(Json::from(memberships.into_iter().map(|(member, organization)| OrganizationInfo {
+				organization_id: hex::encode(organization.organization_id),
+				name: organization.name,
+				role: member.role,
+				created_at: organization.created_at.and_utc(),
+			}).collect::<Vec<_>>()));
+		}
+		Err(error) => {
+			log::error!("Error while listing organizations: {:?}", error);
+			return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CreateOrganizationData {
+	pub name: String,
+}
+
+/// Creates an organization with the user as its first admin
+#[post("/", data = "<data>")]
+async fn create_organization(database: MainDatabase, auth: AuthenticatedUser, data: Json<CreateOrganizationData>) -> Result<Json<OrganizationInfo>, ErrorResponse> {
+	let name = data.name.trim();
+	if name.is_empty() || name.chars().count() > MAX_ORGANIZATION_NAME_LENGTH {
+		return Err(error_response(Status::BadRequest, "InvalidName", &format!("Organization name must have from 1 to {} characters", MAX_ORGANIZATION_NAME_LENGTH)));
+	}
+
+	let mut organization_id = [0u8; 16];
+	rand::thread_rng().fill(&mut organization_id);
+	let now = Utc::now().naive_utc();
+	let organization = Organization {
+		organization_id: Vec::from(organization_id),
+		name: String::from(name),
+		created_at: now,
+	};
+	let member = OrganizationMember {
+		organization_id: Vec::from(organization_id),
+		user_id: auth.user.user_id,
+		role: DeviceRole::Admin,
+		created_at: now,
+	};
+	let inserted = organization.clone();
+	let created = database.run(move |conn| conn.transaction(|conn| {
+		insert_into(organization_dsl::organization).values(inserted).execute(conn)?;
+		return insert_into(member_dsl::organization_member).values(member).execute(conn);
+	})).await;
+	if let Err(error) = created {
+		log::error!("Error while creating organization: {:?}", error);
+		return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+	}
+	return Ok(Json::from(OrganizationInfo {
+		organization_id: hex::encode(organization.organization_id),
+		name: organization.name,
+		role: DeviceRole::Admin,
+		created_at: organization.created_at.and_utc(),
+	}));
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MemberInfo {
+	pub username: String,
+	pub role: DeviceRole,
+	pub created_at: DateTime<Utc>,
+}
+
+/// Lists members of the organization, visible to all of its members
+#[get("/<id>/members")]
+async fn list_members(database: MainDatabase, auth: AuthenticatedUser, id: &str) -> Result<Json<Vec<MemberInfo>>, ErrorResponse> {
+	let membership = find_membership(&database, &auth.user, id).await?;
+	let query = member_dsl::organization_member
+		.inner_join(users_dsl::users)
+		.filter(member_dsl::organization_id.eq(membership.organization_id))
+		.order(users_dsl::username);
+	match database.run(move |conn| query.load::<(OrganizationMember, User)>(conn)).await {
+		Ok(members) => {
+			return Ok(Json::from(members.into_iter().map(|(member, user)| MemberInfo {
+				username: user.username,
+				role: member.role,
+				created_at: member.created_at.and_utc(),
+			}).collect::<Vec<_>>()));
+		}
+		Err(error) => {
+			log::error!("Error while listing organization members: {:?}", error);
+			return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+		}
+	}
+}
+
+/// Whether the organization would be left without admins after `user_id` stops being one
+fn is_last_admin(conn: &mut diesel::SqliteConnection, organization_id: &[u8], user_id: &[u8]) -> diesel::QueryResult<bool> {
+	let other_admins = member_dsl::organization_member
+		.filter(member_dsl::organization_id.eq(organization_id))
+		.filter(member_dsl::role.eq(DeviceRole::Admin))
+		.filter(member_dsl::user_id.ne(user_id))
+		.count()
+		.get_result::<i64>(conn)?;
+	return Ok(other_admins == 0);
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetMemberData {
+	pub role: DeviceRole,
+}
+
+/// Adds the user to the organization, or changes the role of a member. Only admins can manage members
+#[put("/<id>/members/<username>", data = "<data>")]
+async fn set_member(database: MainDatabase, auth: AuthenticatedUser, id: &str, username: &str, data: Json<SetMemberData>) -> Result<Json<MemberInfo>, ErrorResponse> {
+	let membership = find_admin_membership(&database, &auth.user, id).await?;
+	let user = find_user(&database, username).await?;
+
+	let member = OrganizationMember {
+		organization_id: membership.organization_id,
+		user_id: user.user_id,
+		role: data.role,
+		created_at: Utc::now().naive_utc(),
+	};
+	let inserted = member.clone();
+	let updated = database.run(move |conn| conn.transaction(|conn| {
+		if inserted.role != DeviceRole::Admin && is_last_admin(conn, &inserted.organization_id, &inserted.user_id)? {
+			return Ok(false);
+		}
+		replace_into(member_dsl::organization_member).values(inserted).execute(conn)?;
+		return Ok::<_, diesel::result::Error>(true);
+	})).await;
+	match updated {
+		Ok(true) => {
+			return Ok(Json::from(MemberInfo {
+				username: user.username,
+				role: member.role,
+				created_at: member.created_at.and_utc(),
+			}));
+		}
+		Ok(false) => {
+			return Err(error_response(Status::Conflict, "LastAdmin", "Organization has to keep at least one admin"));
+		}
+		Err(error) => {
+			log::error!("Error while changing organization member: {:?}", error);
+			return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+		}
+	}
+}
+
+/// Removes the member from the organization. Admins can remove anyone, and any member can leave
+#[delete("/<id>/members/<username>")]
+async fn remove_member(database: MainDatabase, auth: AuthenticatedUser, id: &str, username: &str) -> Result<Json<MemberInfo>, ErrorResponse> {
+	let membership = if username == auth.user.username {
+		find_membership(&database, &auth.user, id).await?
+	} else {
+		find_admin_membership(&database, &auth.user, id).await?
+	};
+	let user = find_user(&database, username).await?;
+
+	let organization_id = membership.organization_id;
+	let user_id = user.user_id;
+	let removed = database.run(move |conn| conn.transaction(|conn| {
+		let member = member_dsl::organization_member.find((organization_id.clone(), user_id.clone())).first::<OrganizationMember>(conn)?;
+		if member.role == DeviceRole::Admin && is_last_admin(conn, &organization_id, &user_id)? {
+			return Ok(None);
+		}
+		diesel::delete(member_dsl::organization_member.find((organization_id, user_id))).execute(conn)?;
+		return Ok::<_, diesel::result::Error>(Some(member));
+	})).await;
+	match removed {
+		Ok(Some(member)) => {
+			return Ok(Json::from(MemberInfo {
+				username: user.username,
+				role: member.role,
+				created_at: member.created_at.and_utc(),
+			}));
+		}
+		Ok(None) => {
+			return Err(error_response(Status::Conflict, "LastAdmin", "Organization has to keep at least one admin"));
+		}
+		Err(diesel::result::Error::NotFound) => {
+			return Err(error_response(Status::NotFound, "MemberNotFound", "User is not a member of the organization"));
+		}
+		Err(error) => {
+			log::error!("Error while removing organization member: {:?}", error);
+			return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use rocket::local::asynchronous::Client;
+
+	use crate::{device_routes::{DeviceInfo, PairingTokenInfo, UpdateDeviceData}, model::{Device, DeviceApproval, Pairing}, routes_common::Error, tests_common};
+	use super::*;
+
+	async fn create(client: &Client, token: &str, name: &str) -> OrganizationInfo {
+		let response = client.post("/organization").header(tests_common::bearer(token)).json(&CreateOrganizationData { name: String::from(name) }).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		return response.into_json::<OrganizationInfo>().await.unwrap();
+	}
+
+	async fn set_role(client: &Client, token: &str, organization: &OrganizationInfo, username: &str, role: DeviceRole) -> Status {
+		let response = client.put(format!("/organization/{}/members/{}", organization.organization_id, username))
+			.header(tests_common::bearer(token))
+			.json(&SetMemberData { role })
+			.dispatch().await;
+		return response.status();
+	}
+
+	#[rocket::async_test]
+	async fn membership_test() {
+		let client = tests_common::create_local_async_client().await;
+		let user = tests_common::setup_user(&client).await;
+		let other_user = tests_common::setup_other_user(&client).await;
+		let token = tests_common::login(&client, &user).await;
+		let other_token = tests_common::login(&client, &other_user).await;
+
+		let response = client.post("/organization").header(tests_common::bearer(&token)).json(&CreateOrganizationData { name: String::from(" ") }).dispatch().await;
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "InvalidName");
+		let organization = create(&client, &token, "Acme").await;
+		assert_eq!(organization.role, DeviceRole::Admin);
+		let members_url = format!("/organization/{}/members", organization.organization_id);
+
+		let response = client.get(members_url.clone()).header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "OrganizationNotFound");
+		assert_eq!(set_role(&client, &token, &organization, &other_user.username, DeviceRole::Viewer).await, Status::Ok);
+		assert_eq!(set_role(&client, &other_token, &organization, &other_user.username, DeviceRole::Admin).await, Status::Forbidden);
+		assert_eq!(set_role(&client, &token, &organization, &user.username, DeviceRole::Operator).await, Status::Conflict);
+
+		let response = client.get("/organization").header(tests_common::bearer(&other_token)).dispatch().await;
+		let organizations = response.into_json::<Vec<OrganizationInfo>>().await.unwrap();
+		assert_eq!(organizations.len(), 1);
+		assert_eq!(organizations[0].name, "Acme");
+		assert_eq!(organizations[0].role, DeviceRole::Viewer);
+		let response = client.get(members_url.clone()).header(tests_common::bearer(&other_token)).dispatch().await;
+		let members = response.into_json::<Vec<MemberInfo>>().await.unwrap();
+		assert_eq!(members.iter().map(|member| (member.username.as_str(), member.role)).collect::<Vec<_>>(), vec![
+			(user.username.as_str(), DeviceRole::Admin),
+			(other_user.username.as_str(), DeviceRole::Viewer),
+		]);
+
+		let response = client.delete(format!("{}/{}", members_url, user.username)).header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "LastAdmin");
+		let response = client.delete(format!("{}/{}", members_url, other_user.username)).header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		let response = client.get("/organization").header(tests_common::bearer(&other_token)).dispatch().await;
+		assert!(response.into_json::<Vec<OrganizationInfo>>().await.unwrap().is_empty());
+	}
+
+	#[rocket::async_test]
+	async fn organization_devices() {
+		let client = tests_common::create_local_async_client().await;
+		let user = tests_common::setup_user(&client).await;
+		let other_user = tests_common::setup_other_user(&client).await;
+		let token = tests_common::login(&client, &user).await;
+		let other_token = tests_common::login(&client, &other_user).await;
+		let organization = create(&client, &token, "Acme").await;
+		assert_eq!(set_role(&client, &token, &organization, &other_user.username, DeviceRole::Viewer).await, Status::Ok);
+
+		let url = format!("/device/pairing-token?organization={}", organization.organization_id);
+		let response = client.post(url.clone()).header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.status(), Status::Forbidden);
+		assert_eq!(set_role(&client, &token, &organization, &other_user.username, DeviceRole::Operator).await, Status::Ok);
+		let response = client.post(url).header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		let pairing_token = hex::decode(response.into_json::<PairingTokenInfo>().await.unwrap().token).unwrap();
+
+		let database = MainDatabase::get_one(client.rocket()).await.unwrap();
+		let new_device = Device {
+			device_id: vec![7; 16],
+			mac_address: vec![8; 6],
+			auth_key: vec![9; 16],
+			registration_first_stage: false,
+			user_id: Vec::new(),
+			last_seen: None,
+			name: None,
+			location: None,
+			timezone: None,
+			approval: DeviceApproval::Pending,
+			created_at: Utc::now().naive_utc(),
+			mac_conflict: false,
+			organization_id: None,
+			registration_token: None,
+		};
+		let pairing = database.run(move |conn| Device::insert_paired(conn, pairing_token, new_device, Utc::now().naive_utc())).await.unwrap();
+		let Pairing::New(device) = pairing else { panic!("Device wasn't inserted") };
+		assert_eq!(device.user_id, other_user.user_id);
+		let device_url = format!("/device/{}", hex::encode(&device.device_id));
+
+		let response = client.get("/device").header(tests_common::bearer(&token)).dispatch().await;
+		let devices = response.into_json::<Vec<DeviceInfo>>().await.unwrap();
+		assert_eq!(devices.len(), 1);
+		assert_eq!(devices[0].organization_id.as_ref(), Some(&organization.organization_id));
+		assert_eq!(devices[0].role, None);
+
+		// The operator registered the device, but it belongs to the organization
+		let response = client.post(format!("{}/approve", device_url)).header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.status(), Status::Forbidden);
+		let response = client.post(format!("{}/approve", device_url)).header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		let response = client.patch(device_url.clone()).header(tests_common::bearer(&other_token)).json(&UpdateDeviceData { name: Some(String::from("Gate")), ..Default::default() }).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		assert_eq!(response.into_json::<DeviceInfo>().await.unwrap().role, Some(DeviceRole::Operator));
+
+		let response = client.delete(format!("/organization/{}/members/{}", organization.organization_id, other_user.username)).header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		let response = client.get(device_url).header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.status(), Status::NotFound);
+		let response = client.get("/device").header(tests_common::bearer(&other_token)).dispatch().await;
+		assert!(response.into_json::<Vec<DeviceInfo>>().await.unwrap().is_empty());
+	}
+}
@@ -0,0 +1,17 @@
+use rocket::{http::Status, response::status, serde::json::Json};
+use serde::{Deserialize, Serialize};
+
+/// Error body returned by every API route
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Error {
+	pub code: String,
+	pub explanation: String,
+}
+
+pub type ErrorResponse = status::Custom<Json<Error>>;
+
+pub fn error_response(status: Status, code: &str, explanation: &str) -> ErrorResponse {
+	return status::Custom(status, Json::from(Error {
+		code: String::from(code), explanation: String::from(explanation),
+	}));
+}
@@ -7,6 +7,88 @@ diesel::table! {
         auth_key -> Binary,
         registration_first_stage -> Bool,
         user_id -> Binary,
+        last_seen -> Nullable<Timestamp>,
+        name -> Nullable<Text>,
+        location -> Nullable<Text>,
+        timezone -> Nullable<Text>,
+        registration_token -> Nullable<Binary>,
+        approval -> Text,
+        created_at -> Timestamp,
+        mac_conflict -> Bool,
+        organization_id -> Nullable<Binary>,
+    }
+}
+
+diesel::table! {
+    device_access (device_id, user_id) {
+        device_id -> Binary,
+        user_id -> Binary,
+        role -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    device_tag (device_id, tag) {
+        device_id -> Binary,
+        tag -> Text,
+    }
+}
+
+diesel::table! {
+    device_transfer (device_id) {
+        device_id -> Binary,
+        from_user_id -> Binary,
+        to_user_id -> Binary,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    organization (organization_id) {
+        organization_id -> Binary,
+        name -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    organization_member (organization_id, user_id) {
+        organization_id -> Binary,
+        user_id -> Binary,
+        role -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    pairing_token (token) {
+        token -> Binary,
+        user_id -> Binary,
+        expires_at -> Timestamp,
+        organization_id -> Nullable<Binary>,
+    }
+}
+
+diesel::table! {
+    recording (recording_id) {
+        recording_id -> Integer,
+        device_id -> Binary,
+        captured_at -> Timestamp,
+        size -> Integer,
+        path -> Text,
+    }
+}
+
+diesel::table! {
+    refresh_token (token_hash) {
+        token_hash -> Binary,
+        session_id -> Binary,
+        user_id -> Binary,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        rotated -> Bool,
     }
 }
 
@@ -16,12 +98,33 @@ diesel::table! {
         username -> Text,
         password -> Text,
         email -> Text,
+        is_admin -> Bool,
+        disabled -> Bool,
     }
 }
 
+diesel::joinable!(device -> organization (organization_id));
 diesel::joinable!(device -> users (user_id));
+diesel::joinable!(device_access -> device (device_id));
+diesel::joinable!(device_access -> users (user_id));
+diesel::joinable!(device_tag -> device (device_id));
+diesel::joinable!(device_transfer -> device (device_id));
+diesel::joinable!(organization_member -> organization (organization_id));
+diesel::joinable!(organization_member -> users (user_id));
+diesel::joinable!(pairing_token -> organization (organization_id));
+diesel::joinable!(pairing_token -> users (user_id));
+diesel::joinable!(recording -> device (device_id));
+diesel::joinable!(refresh_token -> users (user_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
     device,
+    device_access,
+    device_tag,
+    device_transfer,
+    organization,
+    organization_member,
+    pairing_token,
+    recording,
+    refresh_token,
     users,
 );
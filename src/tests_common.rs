@@ -1,10 +1,14 @@
-use std::env;
+use std::{collections::HashMap, net::Ipv4Addr, path::PathBuf, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}, time::Duration};
 
-use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+use diesel::{insert_into, Connection, ExpressionMethods, QueryDsl, RunQueryDsl, SqliteConnection};
 use diesel_migrations::{EmbeddedMigrations, MigrationHarness};
-use rocket::{fairing::AdHoc, http::Status, local::{asynchronous, blocking::Client}};
+use rocket::{fairing::AdHoc, futures::{SinkExt, StreamExt}, http::{Header, Status}, local::{asynchronous, blocking::Client}, tokio::{io::{duplex, DuplexStream}, net::TcpStream, spawn}, Build, Rocket};
+use tokio_util::sync::CancellationToken;
 
-use crate::{model::User, rocket, schema::users::dsl::*, user_routes::RegisterUserData, MainDatabase};
+use crate::{model::{Device, DeviceApproval, PairingToken, User}, rocket, schema::users::dsl::*, user_routes::{LoginResult, LoginUserData, RegisterUserData}, MainDatabase};
+use crate::device_connector::{codec::{ApplicationPacketCodec, PacketStream}, handle_connection, packets::{ApplicationPacket, InitiateConnectionPacket, Message, PacketHeader}, store::DeviceStore, ConnectionLimits, DeviceBridge, SessionList};
+use crate::schema::device::dsl as device_dsl;
+use crate::schema::pairing_token::dsl as pairing_dsl;
 
 
 pub const MIGRATIONS: EmbeddedMigrations = diesel_migrations::embed_migrations!("./migrations");
@@ -19,37 +23,230 @@ fn migration_fairing() -> AdHoc {
 	});
 }
 
+static DATABASE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Creates the application with its own in-memory database.
+/// Named shared-cache databases are used so all pooled connections see the same data.
+/// The device bridge binds free ports so tests can run in parallel
+pub fn create_test_rocket() -> Rocket<Build> {
+	let database_url = format!("file:test_database_{}?mode=memory&cache=shared", DATABASE_COUNTER.fetch_add(1, Ordering::Relaxed));
+	let rocket = rocket();
+	let figment = rocket.figment().clone()
+		.merge(("databases.main.url", database_url))
+		.merge(("device_bridge.tcp_port", 0))
+		.merge(("device_bridge.udp_port", 0));
+	return rocket.configure(figment).attach(migration_fairing());
+}
+
 pub fn create_local_client() -> Client {
-	env::set_var("ROCKET_DATABASES", "{main={url=\":memory:\"}}");
-	let rocket = rocket().attach(migration_fairing());
-	let client = Client::tracked(rocket).unwrap();
+	let client = Client::tracked(create_test_rocket()).unwrap();
 	return client;
 }
 
 pub async fn create_local_async_client() -> asynchronous::Client {
-	env::set_var("ROCKET_DATABASES", "{main={url=\":memory:\"}}");
-	let rocket = rocket().attach(migration_fairing());
-	let client = asynchronous::Client::tracked(rocket).await.unwrap();
+	let client = asynchronous::Client::tracked(create_test_rocket()).await.unwrap();
 	return client;
 }
 
-pub async fn setup_user(client: &asynchronous::Client) -> User {
+async fn register_user(client: &asynchronous::Client, new_username: &str, new_email: &str) -> User {
 	let response = client.post("/user/register").json(&RegisterUserData {
-		email: String::from("email@example.com"),
+		email: String::from(new_email),
 		password: String::from("password1"),
-		username: String::from("new_username"),
+		username: String::from(new_username),
 	}).dispatch().await;
 	assert_eq!(response.status(), Status::Ok);
 	let database = MainDatabase::get_one(client.rocket()).await.unwrap();
-	let mut created_user = database.run(|conn| users.filter(username.eq("new_username")).first::<User>(conn)).await.unwrap();
+	let query = users.filter(username.eq(String::from(new_username)));
+	let mut created_user = database.run(move |conn| query.first::<User>(conn)).await.unwrap();
 	created_user.password = String::from("password1");
 	return created_user;
 }
 
+pub async fn setup_user(client: &asynchronous::Client) -> User {
+	return register_user(client, "new_username", "email@example.com").await;
+}
+
+/// Creates a second user, for checking access to resources of [`setup_user`]
+pub async fn setup_other_user(client: &asynchronous::Client) -> User {
+	return register_user(client, "other_username", "other@example.com").await;
+}
+
+/// Logs the user in and returns the token
+pub async fn login(client: &asynchronous::Client, user: &User) -> String {
+	let response = client.post("/user/login").json(&LoginUserData {
+		username: user.username.clone(),
+		password: user.password.clone(),
+	}).dispatch().await;
+	assert_eq!(response.status(), Status::Ok);
+	return response.into_json::<LoginResult>().await.unwrap().token;
+}
+
+/// `Authorization` header with the token returned by [`login`]
+pub fn bearer(token: &str) -> Header<'static> {
+	return Header::new("Authorization", format!("Bearer {}", token));
+}
+
+/// Fully registered and approved device owned by `user`
+fn registered_device(user: &User) -> Device {
+	return Device {
+		device_id: vec![1; 16],
+		mac_address: vec![2; 6],
+		auth_key: vec![3; 16],
+		registration_first_stage: false,
+		user_id: user.user_id.clone(),
+		last_seen: None,
+		name: None,
+		location: None,
+		timezone: None,
+		approval: DeviceApproval::Approved,
+		created_at: chrono::Utc::now().naive_utc(),
+		mac_conflict: false,
+		organization_id: None,
+		registration_token: None,
+	};
+}
+
+/// Inserts a fully registered device owned by `user`
+pub async fn setup_device(client: &asynchronous::Client, user: &User) -> Device {
+	let new_device = registered_device(user);
+	let database = MainDatabase::get_one(client.rocket()).await.unwrap();
+	let query = insert_into(device_dsl::device).values(new_device.clone());
+	database.run(move |conn| query.execute(conn)).await.unwrap();
+	return new_device;
+}
+
+/// Connects the device to the device bridge of the application over TCP, the way a real device does.
+/// Returns the connection after the `InitiateConnection` handshake, with the ID of the started session
+pub async fn connect_device(client: &asynchronous::Client, device: &Device) -> (PacketStream<TcpStream>, [u8; 16]) {
+	let bridge = client.rocket().state::<DeviceBridge>().unwrap();
+	let socket = TcpStream::connect((Ipv4Addr::LOCALHOST, bridge.tcp_address().port())).await.unwrap();
+	let mut connection = ApplicationPacketCodec::new(bridge.config().max_packet_body_size).framed(socket);
+	connection.send(ApplicationPacket {
+		header: PacketHeader { session_id: [0; 16], buffer_size: 32, is_response: false },
+		message: Message::InitiateConnection(InitiateConnectionPacket {
+			camera_id: device.device_id.clone().try_into().unwrap(),
+			auth_key: device.auth_key.clone().try_into().unwrap(),
+		}),
+	}).await.unwrap();
+	let session_id = connection.next().await.unwrap().unwrap().header.session_id;
+	return (connection, session_id);
+}
+
+/// In-memory database for running device connection handlers without the whole application
+pub struct TestStore(Mutex<SqliteConnection>);
+
+impl TestStore {
+	pub fn new() -> Self {
+		let mut connection = SqliteConnection::establish(":memory:").unwrap();
+		connection.run_pending_migrations(MIGRATIONS).unwrap();
+		return Self(Mutex::new(connection));
+	}
+}
+
+#[rocket::async_trait]
+impl DeviceStore for TestStore {
+	async fn run<F, R>(&self, f: F) -> R
+	where
+		F: FnOnce(&mut SqliteConnection) -> R + Send + 'static,
+		R: Send + 'static,
+	{
+		return f(&mut self.0.lock().unwrap());
+	}
+}
+
+/// Device connection handlers backed by a [`TestStore`], with devices connecting over in-memory streams
+pub struct TestBridge {
+	pub sessions: SessionList,
+	pub store: Arc<TestStore>,
+	/// Unique directory for frames, not created until something is stored
+	pub storage_root: PathBuf,
+	canceller: CancellationToken,
+}
+
+impl TestBridge {
+	pub const MAX_BODY_SIZE: u32 = 256;
+	pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_millis(500);
+
+	pub fn new() -> Self {
+		return Self {
+			sessions: Arc::new(Mutex::new(HashMap::new())),
+			store: Arc::new(TestStore::new()),
+			storage_root: std::env::temp_dir().join(format!("camera-server-test-{}", hex::encode(rand::random::<[u8; 8]>()))),
+			canceller: CancellationToken::new(),
+		};
+	}
+
+	/// Opens a connection handled the same way as a TCP connection from a device
+	pub fn connect(&self) -> PacketStream<DuplexStream> {
+		let (device_side, server_side) = duplex(4096);
+		spawn(handle_connection(server_side, "127.0.0.1:40000".parse().unwrap(), ConnectionLimits { max_body_size: Self::MAX_BODY_SIZE, handshake_timeout: Self::HANDSHAKE_TIMEOUT }, self.storage_root.clone(), self.sessions.clone(), self.canceller.clone(), self.store.clone()));
+		return ApplicationPacketCodec::new(Self::MAX_BODY_SIZE).framed(device_side);
+	}
+
+	async fn insert_user(&self, id: u8, new_username: &str, new_email: &str) -> User {
+		let new_user = User {
+			user_id: vec![id; 16],
+			username: String::from(new_username),
+			password: String::from("password1"),
+			email: String::from(new_email),
+			is_admin: false,
+			disabled: false,
+		};
+		let query = insert_into(users).values(new_user.clone());
+		self.store.run(move |conn| query.execute(conn)).await.unwrap();
+		return new_user;
+	}
+
+	pub async fn setup_user(&self) -> User {
+		return self.insert_user(4, "new_username", "email@example.com").await;
+	}
+
+	pub async fn setup_other_user(&self) -> User {
+		return self.insert_user(5, "other_username", "other@example.com").await;
+	}
+
+	/// Creates a pairing token of `user` that expires after `lifetime`, which can be negative
+	pub async fn setup_pairing_token(&self, user: &User, lifetime: chrono::TimeDelta) -> [u8; 16] {
+		let token = rand::random::<[u8; 16]>();
+		let query = insert_into(pairing_dsl::pairing_token).values(PairingToken {
+			token: Vec::from(token),
+			user_id: user.user_id.clone(),
+			expires_at: (chrono::Utc::now() + lifetime).naive_utc(),
+			organization_id: None,
+		});
+		self.store.run(move |conn| query.execute(conn)).await.unwrap();
+		return token;
+	}
+
+	/// Same as [`setup_device`], the device connects with auth key `[3; 16]`
+	pub async fn setup_device(&self, user: &User) -> Device {
+		let new_device = registered_device(user);
+		let query = insert_into(device_dsl::device).values(new_device.clone());
+		self.store.run(move |conn| query.execute(conn)).await.unwrap();
+		return new_device;
+	}
+
+	pub async fn set_approval(&self, id: [u8; 16], approval: DeviceApproval) {
+		let query = diesel::update(device_dsl::device.find(id)).set(device_dsl::approval.eq(approval));
+		self.store.run(move |conn| query.execute(conn)).await.unwrap();
+	}
+
+	pub async fn get_device(&self, id: [u8; 16]) -> Option<Device> {
+		let query = device_dsl::device.find(id);
+		return self.store.run(move |conn| query.first::<Device>(conn)).await.ok();
+	}
+}
+
+impl Drop for TestBridge {
+	fn drop(&mut self) {
+		self.canceller.cancel();
+	}
+}
+
 #[rocket::async_test]
 async fn verify_user_creation() {
 	let client = create_local_async_client().await;
 	let created_user = setup_user(&client).await;
 	assert_eq!(created_user.username, "new_username");
 	assert_eq!(created_user.email, "email@example.com");
-}
\ No newline at end of file
+}
@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use diesel::{replace_into, BoolExpressionMethods, ExpressionMethods, QueryDsl, RunQueryDsl};
+use rocket::{delete, get, post, http::Status, routes, serde::json::Json, Route, State};
+use serde::{Deserialize, Serialize};
+
+use crate::{access_routes::find_user, auth::AuthenticatedUser, device_connector::DeviceBridge, device_routes::{find_owned_device, load_tags, parse_device_id, DeviceInfo}, model::{DeviceTransfer, User}, routes_common::{error_response, ErrorResponse}, MainDatabase};
+use crate::schema::device_transfer::dsl as transfer_dsl;
+use crate::schema::users::dsl as users_dsl;
+
+/// Time for which the recipient can accept a transfer
+const TRANSFER_LIFETIME: TimeDelta = TimeDelta::days(7);
+
+pub fn routes() -> Vec<Route> {
+	return routes![
+		list_transfers,
+		offer_transfer,
+		accept_transfer,
+		cancel_transfer
+	];
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TransferInfo {
+	/// Hex encoded
+	pub device_id: String,
+	pub from_username: String,
+	pub to_username: String,
+	pub created_at: DateTime<Utc>,
+	pub expires_at: DateTime<Utc>,
+}
+
+/// Looks up usernames of both sides of the transfers
+async fn transfer_infos(database: &MainDatabase, transfers: Vec<DeviceTransfer>) -> Result<Vec<TransferInfo>, ErrorResponse> {
+	let user_ids: Vec<Vec<u8>> = transfers.iter().flat_map(|transfer| [transfer.from_user_id.clone(), transfer.to_user_id.clone()]).collect();
+	let query = users_dsl::users.filter(users_dsl::user_id.eq_any(user_ids));
+	let usernames: HashMap<Vec<u8>, String> = match database.run(move |conn| query.load::<User>(conn)).await {
+		Ok(users) => users.into_iter().map(|user| (user.user_id, user.username)).collect(),
+		Err(error) => {
+			log::error!("Error while retrieving users of transfers: {:?}", error);
+			return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+		}
+	};
+	return Ok(transfers.into_iter().map(|transfer| TransferInfo {
+		device_id: hex::encode(transfer.device_id),
+		from_username: usernames.get(&transfer.from_user_id).cloned().unwrap_or_default(),
+		to_username: usernames.get(&transfer.to_user_id).cloned().unwrap_or_default(),
+		created_at: transfer.created_at.and_utc(),
+		expires_at: transfer.expires_at.and_utc(),
+	}).collect());
+}
+
+/// Lists unexpired transfers offered by the user or to the user
+#[get("/transfers")]
+async fn list_transfers(database: MainDatabase, auth: AuthenticatedUser) -> Result<Json<Vec<TransferInfo>>, ErrorResponse> {
+	let user_id = auth.user.user_id;
+	let query = transfer_dsl::device_transfer
+		.filter(transfer_dsl::from_user_id.eq(user_id.clone()).or(transfer_dsl::to_user_id.eq(user_id)))
+		.filter(transfer_dsl::expires_at.gt(Utc::now().naive_utc()))
+		.order(transfer_dsl::created_at);
+	match database.run(move |conn| query.load::<DeviceTransfer>(conn)).await {
+		Ok(transfers) => {
+			return Ok(Json::from(transfer_infos(&database, transfers).await?));
+		}
+		Err(error) => {
+			log::error!("Error while listing transfers: {:?}", error);
+			return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TransferDeviceData {
+	/// User that should become the new owner
+	pub username: String,
+}
+
+/// Offers the device to another user. Replaces the previous offer of the device
+#[post("/<id>/transfer", data = "<data>")]
+async fn offer_transfer(database: MainDatabase, auth: AuthenticatedUser, id: &str, data: Json<TransferDeviceData>) -> Result<Json<TransferInfo>, ErrorResponse> {
+	let device = find_owned_device(&database, &auth.user, id).await?;
+	if device.registration_first_stage {
+		return Err(error_response(Status::Conflict, "RegistrationIncomplete", "Device has to finish registration before it can be transferred"));
+	}
+	if device.organization_id.is_some() {
+		return Err(error_response(Status::Conflict, "OrganizationDevice", "Devices of organizations can't be transferred to users"));
+	}
+
+	let recipient = find_user(&database, &data.username).await?;
+	if recipient.user_id == auth.user.user_id {
+		return Err(error_response(Status::BadRequest, "InvalidRecipient", "Device already belongs to this user"));
+	}
+
+	let now = Utc::now();
+	let transfer = DeviceTransfer {
+		device_id: device.device_id,
+		from_user_id: auth.user.user_id,
+		to_user_id: recipient.user_id,
+		created_at: now.naive_utc(),
+		expires_at: (now + TRANSFER_LIFETIME).naive_utc(),
+	};
+	let inserted = transfer.clone();
+	if let Err(error) = database.run(move |conn| replace_into(transfer_dsl::device_transfer).values(inserted).execute(conn)).await {
+		log::error!("Error while creating transfer: {:?}", error);
+		return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+	}
+	return Ok(Json::from(TransferInfo {
+		device_id: hex::encode(transfer.device_id),
+		from_username: auth.user.username,
+		to_username: recipient.username,
+		created_at: now,
+		expires_at: now + TRANSFER_LIFETIME,
+	}));
+}
+
+/// Makes the user the owner of the device offered to them. The connected device stays connected
+#[post("/<id>/transfer/accept")]
+async fn accept_transfer(database: MainDatabase, bridge: &State<DeviceBridge>, auth: AuthenticatedUser, id: &str) -> Result<Json<DeviceInfo>, ErrorResponse> {
+	let device_id = parse_device_id(id)?;
+	let recipient = auth.user.user_id.clone();
+	let accepted = database.run(move |conn| DeviceTransfer::accept(conn, device_id, recipient, Utc::now().naive_utc())).await;
+	let device = match accepted {
+		Ok(device) => device,
+		Err(diesel::result::Error::NotFound) => {
+			return Err(error_response(Status::NotFound, "TransferNotFound", "Device wasn't offered to this user or the offer expired"));
+		}
+		Err(error) => {
+			log::error!("Error while accepting transfer: {:?}", error);
+			return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+		}
+	};
+	bridge.rebind_owner(device.device_id.clone().try_into().unwrap(), auth.user.user_id.try_into().unwrap());
+	let tags = load_tags(&database, vec![device.device_id.clone()]).await?.remove(&device.device_id).unwrap_or_default();
+	return Ok(Json::from(DeviceInfo::new(device, None, tags, bridge)));
+}
+
+/// Withdraws the offer as its sender, or declines it as its recipient
+#[delete("/<id>/transfer")]
+async fn cancel_transfer(database: MainDatabase, auth: AuthenticatedUser, id: &str) -> Result<Json<TransferInfo>, ErrorResponse> {
+	let device_id = parse_device_id(id)?;
+	let user_id = auth.user.user_id;
+	let cancelled = database.run(move |conn| {
+		let query = transfer_dsl::device_transfer
+			.find(device_id)
+			.filter(transfer_dsl::from_user_id.eq(user_id.clone()).or(transfer_dsl::to_user_id.eq(user_id)));
+		let transfer = query.first::<DeviceTransfer>(conn)?;
+		diesel::delete(transfer_dsl::device_transfer.find(transfer.device_id.clone())).execute(conn)?;
+		return Ok::<_, diesel::result::Error>(transfer);
+	}).await;
+	match cancelled {
+		Ok(transfer) => {
+			return Ok(Json::from(transfer_infos(&database, vec![transfer]).await?.remove(0)));
+		}
+		Err(diesel::result::Error::NotFound) => {
+			return Err(error_response(Status::NotFound, "TransferNotFound", "Device has no transfer involving this user"));
+		}
+		Err(error) => {
+			log::error!("Error while cancelling transfer: {:?}", error);
+			return Err(error_response(Status::InternalServerError, "InternalError", "Unknown error. Contact administrator."));
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{routes_common::Error, tests_common};
+	use super::*;
+
+	#[rocket::async_test]
+	async fn transfer_flow() {
+		let client = tests_common::create_local_async_client().await;
+		let user = tests_common::setup_user(&client).await;
+		let device = tests_common::setup_device(&client, &user).await;
+		let other_user = tests_common::setup_other_user(&client).await;
+		let token = tests_common::login(&client, &user).await;
+		let other_token = tests_common::login(&client, &other_user).await;
+		let url = format!("/device/{}/transfer", hex::encode(&device.device_id));
+
+		let response = client.post(url.clone()).header(tests_common::bearer(&token)).json(&TransferDeviceData { username: String::from("missing") }).dispatch().await;
+		assert_eq!(response.status(), Status::NotFound);
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "UserNotFound");
+		let response = client.post(url.clone()).header(tests_common::bearer(&token)).json(&TransferDeviceData { username: user.username.clone() }).dispatch().await;
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "InvalidRecipient");
+		let response = client.post(url.clone()).header(tests_common::bearer(&other_token)).json(&TransferDeviceData { username: user.username.clone() }).dispatch().await;
+		assert_eq!(response.status(), Status::NotFound);
+
+		let response = client.post(url.clone()).header(tests_common::bearer(&token)).json(&TransferDeviceData { username: other_user.username.clone() }).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		let response = client.get("/device/transfers").header(tests_common::bearer(&other_token)).dispatch().await;
+		let transfers = response.into_json::<Vec<TransferInfo>>().await.unwrap();
+		assert_eq!(transfers.len(), 1);
+		assert_eq!(transfers[0].from_username, user.username);
+		assert_eq!(transfers[0].to_username, other_user.username);
+
+		// Only the recipient can accept
+		let response = client.post(format!("{}/accept", url)).header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::NotFound);
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "TransferNotFound");
+
+		let bridge = client.rocket().state::<DeviceBridge>().unwrap();
+		// Kept open so the device stays online
+		let (_connection, _) = tests_common::connect_device(&client, &device).await;
+
+		let response = client.post(format!("{}/accept", url)).header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		assert!(response.into_json::<DeviceInfo>().await.unwrap().status.online);
+		let session = bridge.device_session(device.device_id.clone().try_into().unwrap()).unwrap();
+		assert_eq!(Vec::from(session.owner_id), other_user.user_id);
+
+		let response = client.get(format!("/device/{}", hex::encode(&device.device_id))).header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::NotFound);
+		let response = client.get(format!("/device/{}", hex::encode(&device.device_id))).header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		let response = client.post(format!("{}/accept", url)).header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.status(), Status::NotFound);
+	}
+
+	#[rocket::async_test]
+	async fn cancel_transfer_test() {
+		let client = tests_common::create_local_async_client().await;
+		let user = tests_common::setup_user(&client).await;
+		let device = tests_common::setup_device(&client, &user).await;
+		let other_user = tests_common::setup_other_user(&client).await;
+		let token = tests_common::login(&client, &user).await;
+		let other_token = tests_common::login(&client, &other_user).await;
+		let url = format!("/device/{}/transfer", hex::encode(&device.device_id));
+
+		let response = client.delete(url.clone()).header(tests_common::bearer(&token)).dispatch().await;
+		assert_eq!(response.status(), Status::NotFound);
+
+		client.post(url.clone()).header(tests_common::bearer(&token)).json(&TransferDeviceData { username: other_user.username.clone() }).dispatch().await;
+		let response = client.delete(url.clone()).header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		assert_eq!(response.into_json::<TransferInfo>().await.unwrap().to_username, other_user.username);
+
+		let response = client.post(format!("{}/accept", url)).header(tests_common::bearer(&other_token)).dispatch().await;
+		assert_eq!(response.status(), Status::NotFound);
+		let response = client.get("/device/transfers").header(tests_common::bearer(&token)).dispatch().await;
+		assert!(response.into_json::<Vec<TransferInfo>>().await.unwrap().is_empty());
+	}
+}
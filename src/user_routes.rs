@@ -1,27 +1,30 @@
 use argon2::{password_hash::{rand_core::OsRng, SaltString}, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, TimeDelta, Utc};
 use diesel::{insert_into, result::DatabaseErrorKind, ExpressionMethods, QueryDsl, RunQueryDsl};
-use jsonwebtoken::{encode, EncodingKey, Header};
 use rand::Rng;
-use rocket::{http::Status, post, response::status, routes, serde::json::Json, Route};
+use rocket::{get, http::Status, post, response::status, routes, serde::json::Json, Route};
 use serde::{Deserialize, Serialize};
 
-use crate::{auth::AuthToken, model::User, MainDatabase};
+use crate::{auth::{AuthToken, AuthenticatedUser}, model::{RefreshToken, User}, routes_common::Error, MainDatabase};
+use crate::schema::refresh_token::dsl as refresh_dsl;
 use crate::schema::users::dsl::*;
 
+/// Lifetime of access tokens, clients keep their session going with refresh tokens
+const ACCESS_TOKEN_LIFETIME: TimeDelta = Duration::minutes(15);
+/// Time for which a refresh token can be used, every refresh extends the session by this much
+const REFRESH_TOKEN_LIFETIME: TimeDelta = Duration::days(30);
+
 pub fn routes() -> Vec<Route> {
 	return routes![
 		register_user,
-		login
+		login,
+		refresh,
+		logout,
+		logout_all,
+		me
 	];
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-struct Error {
-	code: String,
-	explanation: String,
-}
-
 type UserResult<T> = Result<Json<T>, status::Custom<Json<Error>>>;
 
 #[derive(Serialize, Debug, Default)]
@@ -55,6 +58,8 @@ async fn register_user(database: MainDatabase, register_user_data: Json<Register
 		username: data.username,
 		password: hashed_password,
 		email: data.email,
+		is_admin: false,
+		disabled: false,
 	});
 	let inserted = database.run(move |c| { query.execute(c) }).await;
 	match inserted {
@@ -76,8 +81,54 @@ async fn register_user(database: MainDatabase, register_user_data: Json<Register
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
-struct LoginResult {
-	token: String
+pub struct LoginResult {
+	pub token: String,
+	/// Expiration time of `token`
+	pub expires_at: DateTime<Utc>,
+	/// Hex encoded, exchanged for a new pair of tokens at `/user/refresh`
+	pub refresh_token: String,
+}
+
+fn internal_error() -> status::Custom<Json<Error>> {
+	return status::Custom(Status::InternalServerError, Json::from(Error {
+		code: String::from("InternalError"), explanation: String::from("Unknown error. Contact administrator.")
+	}));
+}
+
+/// Random refresh token for the client and its hash for the database
+fn generate_refresh_token() -> (String, Vec<u8>) {
+	let token = rand::random::<[u8; 32]>();
+	return (hex::encode(token), RefreshToken::hash(&token));
+}
+
+/// Starts a new session of the user with a fresh pair of tokens
+async fn start_session(database: MainDatabase, user: User) -> UserResult<LoginResult> {
+	let now = Utc::now();
+	let session_id = rand::random::<[u8; 16]>();
+	let (refresh_token, token_hash) = generate_refresh_token();
+	let new_token = RefreshToken {
+		token_hash,
+		session_id: Vec::from(session_id),
+		user_id: user.user_id.clone(),
+		created_at: now.naive_utc(),
+		expires_at: (now + REFRESH_TOKEN_LIFETIME).naive_utc(),
+		rotated: false,
+	};
+	let stored = database.run(move |conn| {
+		diesel::delete(refresh_dsl::refresh_token.filter(refresh_dsl::expires_at.le(now.naive_utc()))).execute(conn)?;
+		return insert_into(refresh_dsl::refresh_token).values(new_token).execute(conn);
+	}).await;
+	match stored {
+		Ok(_) => {
+			let expires_at = now + ACCESS_TOKEN_LIFETIME;
+			let token = AuthToken::new(&user.user_id, user.username, &session_id, expires_at).encode();
+			return Ok(Json::from(LoginResult { token, expires_at, refresh_token }));
+		}
+		Err(error) => {
+			log::error!("Error while starting a session: {:?}", error);
+			return Err(internal_error());
+		}
+	}
 }
 
 #[post("/login", data = "<login_data>")]
@@ -90,12 +141,13 @@ async fn login(database: MainDatabase, login_data: Json<LoginUserData>) -> UserR
 			match PasswordHash::new(&user.password) {
 				Ok(hash) => {
 					match Argon2::default().verify_password(login_data.password.as_bytes(), &hash) {
+						Ok(_) if user.disabled => {
+							return Err(status::Custom(Status::Forbidden, Json::from(Error {
+								code: String::from("AccountDisabled"), explanation: String::from("The account was disabled by an administrator.")
+							})));
+						}
 						Ok(_) => {
-							let token_secret = std::env::var("JWT_SECRET").expect("JWT_SECRET is not set");
-							let expiry_time = Utc::now() + Duration::hours(4);
-							let header = Header::new(jsonwebtoken::Algorithm::HS512);
-							let token = encode(&header, &AuthToken::new(user.username, expiry_time), &EncodingKey::from_secret(token_secret.as_bytes())).unwrap();
-							return Ok(Json::from(LoginResult { token }));
+							return start_session(database, user).await;
 						}
 						Err(argon2::password_hash::Error::Password) => {
 							return Err(status::Custom(Status::Unauthorized, Json::from(Error {
@@ -132,12 +184,110 @@ async fn login(database: MainDatabase, login_data: Json<LoginUserData>) -> UserR
 	}
 }
 
+/// Exchanges a refresh token for a new access token and a new refresh token. The used refresh token stops working,
+/// and using it again logs out its whole session, as it was probably stolen
+#[post("/refresh", data = "<refresh_data>")]
+async fn refresh(database: MainDatabase, refresh_data: Json<RefreshData>) -> UserResult<LoginResult> {
+	let invalid_token = || status::Custom(Status::Unauthorized, Json::from(Error {
+		code: String::from("InvalidRefreshToken"), explanation: String::from("Refresh token is invalid, expired or was logged out")
+	}));
+	let Ok(token) = hex::decode(&refresh_data.refresh_token) else {
+		return Err(invalid_token());
+	};
+	let now = Utc::now();
+	let (refresh_token, new_token_hash) = generate_refresh_token();
+	let result = database.run(move |conn| -> diesel::QueryResult<(User, RefreshToken)> {
+		let rotated = RefreshToken::rotate(conn, RefreshToken::hash(&token), new_token_hash, now.naive_utc(), (now + REFRESH_TOKEN_LIFETIME).naive_utc())?;
+		let user = users.find(rotated.user_id.clone()).first::<User>(conn)?;
+		if user.disabled {
+			RefreshToken::revoke_session(conn, rotated.session_id.clone())?;
+		}
+		return Ok((user, rotated));
+	}).await;
+	match result {
+		Ok((user, _)) if user.disabled => {
+			return Err(status::Custom(Status::Forbidden, Json::from(Error {
+				code: String::from("AccountDisabled"), explanation: String::from("The account was disabled by an administrator.")
+			})));
+		}
+		Ok((user, rotated)) => {
+			let expires_at = now + ACCESS_TOKEN_LIFETIME;
+			let token = AuthToken::new(&user.user_id, user.username, &rotated.session_id, expires_at).encode();
+			return Ok(Json::from(LoginResult { token, expires_at, refresh_token }));
+		}
+		Err(diesel::result::Error::NotFound) => {
+			return Err(invalid_token());
+		}
+		Err(error) => {
+			log::error!("Error while refreshing a token: {:?}", error);
+			return Err(internal_error());
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct LogoutResult {}
+
+/// Ends the session of the access token, invalidating it together with its refresh token
+#[post("/logout")]
+async fn logout(database: MainDatabase, auth: AuthenticatedUser) -> UserResult<LogoutResult> {
+	match database.run(move |conn| RefreshToken::revoke_session(conn, auth.session_id)).await {
+		Ok(_) => {
+			return Ok(Json::from(LogoutResult {}));
+		}
+		Err(error) => {
+			log::error!("Error while logging out: {:?}", error);
+			return Err(internal_error());
+		}
+	}
+}
+
+/// Ends all sessions of the user, including the current one
+#[post("/logout-all")]
+async fn logout_all(database: MainDatabase, auth: AuthenticatedUser) -> UserResult<LogoutResult> {
+	match database.run(move |conn| RefreshToken::revoke_user(conn, auth.user.user_id)).await {
+		Ok(_) => {
+			return Ok(Json::from(LogoutResult {}));
+		}
+		Err(error) => {
+			log::error!("Error while logging out of all sessions: {:?}", error);
+			return Err(internal_error());
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UserInfo {
+	/// Hex encoded, as sent by devices during registration
+	pub user_id: String,
+	pub username: String,
+	pub email: String,
+	pub is_admin: bool,
+}
+
+/// Returns the account of the logged in user
+#[get("/me")]
+async fn me(auth: AuthenticatedUser) -> Json<UserInfo> {
+	let user = auth.user;
+	return Json::from(UserInfo {
+		user_id: hex::encode(user.user_id),
+		username: user.username,
+		email: user.email,
+		is_admin: user.is_admin,
+	});
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct LoginUserData {
 	pub username: String,
 	pub password: String,
 }
 
+#[derive(Deserialize, Serialize, Debug)]
+pub struct RefreshData {
+	pub refresh_token: String,
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct RegisterUserData {
 	pub username: String,
@@ -147,8 +297,6 @@ pub struct RegisterUserData {
 
 #[cfg(test)]
 mod tests {
-	use jsonwebtoken::{decode, DecodingKey, Validation};
-
 	use crate::tests_common;
 	use super::*;
 
@@ -182,11 +330,11 @@ mod tests {
 		assert_eq!(response.status(), Status::Ok);
 		
 		let token = response.into_json::<LoginResult>().await.unwrap().token;
-		let token_secret = std::env::var("JWT_SECRET").expect("JWT_SECRET is not set");
-		let decoded_token = decode::<AuthToken>(&token, &DecodingKey::from_secret(token_secret.as_bytes()), &Validation::new(jsonwebtoken::Algorithm::HS512)).unwrap();
-		println!("{:#?}", decoded_token.header);
-		let token_data = decoded_token.claims;
+		let token_data = AuthToken::decode(&token).unwrap();
 		assert_eq!(token_data.username, user.username);
+		assert_eq!(token_data.sub, hex::encode(&user.user_id));
+		let other_token = tests_common::login(&client, &user).await;
+		assert_ne!(AuthToken::decode(&other_token).unwrap().jti, token_data.jti);
 
 		let request = client.post("/user/login").json(&LoginUserData { password: user.password.clone() + "_wrong", username: user.username.clone() });
 		let response = request.dispatch().await;
@@ -200,4 +348,69 @@ mod tests {
 		let return_code = response.into_json::<Error>().await.unwrap();
 		assert_eq!(return_code.code, "UserNotFound");
 	}
+
+	async fn login_session(client: &rocket::local::asynchronous::Client, user: &User) -> LoginResult {
+		let response = client.post("/user/login").json(&LoginUserData { password: user.password.clone(), username: user.username.clone() }).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		return response.into_json::<LoginResult>().await.unwrap();
+	}
+
+	async fn me_status(client: &rocket::local::asynchronous::Client, token: &str) -> Status {
+		return client.get("/user/me").header(tests_common::bearer(token)).dispatch().await.status();
+	}
+
+	#[rocket::async_test]
+	async fn refresh_test() {
+		let client = tests_common::create_local_async_client().await;
+		let user = tests_common::setup_user(&client).await;
+		let session = login_session(&client, &user).await;
+
+		let response = client.post("/user/refresh").json(&RefreshData { refresh_token: session.refresh_token.clone() }).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		let refreshed = response.into_json::<LoginResult>().await.unwrap();
+		assert_ne!(refreshed.refresh_token, session.refresh_token);
+		assert_eq!(me_status(&client, &refreshed.token).await, Status::Ok);
+		// Access tokens of the session stay valid until they expire
+		assert_eq!(me_status(&client, &session.token).await, Status::Ok);
+
+		let response = client.post("/user/refresh").json(&RefreshData { refresh_token: String::from("not hex") }).dispatch().await;
+		assert_eq!(response.status(), Status::Unauthorized);
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "InvalidRefreshToken");
+
+		// Reusing a rotated token logs out the whole session
+		let response = client.post("/user/refresh").json(&RefreshData { refresh_token: session.refresh_token.clone() }).dispatch().await;
+		assert_eq!(response.status(), Status::Unauthorized);
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "InvalidRefreshToken");
+		let response = client.post("/user/refresh").json(&RefreshData { refresh_token: refreshed.refresh_token.clone() }).dispatch().await;
+		assert_eq!(response.status(), Status::Unauthorized);
+		assert_eq!(me_status(&client, &refreshed.token).await, Status::Unauthorized);
+	}
+
+	#[rocket::async_test]
+	async fn logout_test() {
+		let client = tests_common::create_local_async_client().await;
+		let user = tests_common::setup_user(&client).await;
+		let first = login_session(&client, &user).await;
+		let second = login_session(&client, &user).await;
+		let third = login_session(&client, &user).await;
+
+		let response = client.post("/user/logout").header(tests_common::bearer(&first.token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		let response = client.get("/user/me").header(tests_common::bearer(&first.token)).dispatch().await;
+		assert_eq!(response.status(), Status::Unauthorized);
+		assert_eq!(response.into_json::<Error>().await.unwrap().code, "RevokedToken");
+		let response = client.post("/user/refresh").json(&RefreshData { refresh_token: first.refresh_token.clone() }).dispatch().await;
+		assert_eq!(response.status(), Status::Unauthorized);
+		assert_eq!(me_status(&client, &second.token).await, Status::Ok);
+
+		let response = client.post("/user/logout-all").header(tests_common::bearer(&second.token)).dispatch().await;
+		assert_eq!(response.status(), Status::Ok);
+		assert_eq!(me_status(&client, &second.token).await, Status::Unauthorized);
+		assert_eq!(me_status(&client, &third.token).await, Status::Unauthorized);
+		let response = client.post("/user/refresh").json(&RefreshData { refresh_token: third.refresh_token.clone() }).dispatch().await;
+		assert_eq!(response.status(), Status::Unauthorized);
+
+		let session = login_session(&client, &user).await;
+		assert_eq!(me_status(&client, &session.token).await, Status::Ok);
+	}
 }
\ No newline at end of file